@@ -1,7 +1,60 @@
 #version 460
 
+const int PALETTE_STOPS = 32;
+const int MAX_POINT_LIGHTS = 8;
+
 struct VoxelType {
     vec3 color;
+    float opacity;
+    float emissive;
+    float roughness;
+};
+
+struct PointLight {
+    vec3 position;
+    float range;
+    vec3 color;
+};
+
+// `top` is also the solid color. `bounds_width` is zero if the bounds are hidden.
+struct Background {
+    uint mode;
+    vec3 top;
+    vec3 bottom;
+    vec3 bounds_color;
+    float bounds_width;
+};
+
+// Light colors are multiplied by their intensities.
+struct Lights {
+    vec3 ambient;
+    uint point_light_count;
+    vec3 to_directional;
+    uint shadows;
+    vec3 directional;
+    uint ambient_occlusion;
+    PointLight point_lights[MAX_POINT_LIGHTS];
+};
+
+// Light arriving at a face, split into the part that is scattered by the surface and tinted by
+// its color, and the part that is reflected towards the camera.
+struct Lighting {
+    vec3 diffuse;
+    vec3 specular;
+};
+
+struct Fog {
+    vec3 color;
+    float strength;
+    float near;
+    float far;
+};
+
+struct DepthPalette {
+    uint mode;
+    float near;
+    float far;
+    vec4 stops[PALETTE_STOPS];
 };
 
 struct Camera {
@@ -15,15 +68,39 @@ struct Ray {
     vec3 direction;
 };
 
+const float EPSILON = 1.19209290e-07;
+const uint COLOR_TYPE = 0;
+const uint COLOR_DEPTH = 1;
+// Once the accumulated color is this opaque, nothing behind it is visible anymore.
+const float MAX_ALPHA = 0.99;
+// How far shadow rays start in front of the face, so that they don't hit its own voxel.
+const float SHADOW_BIAS = 0.01;
+const float MAX_FLOAT = 3.40282347e+38;
+const float PI = 3.14159265;
+const uint BACKGROUND_SOLID = 0;
+const uint BACKGROUND_GRADIENT = 1;
+const uint BACKGROUND_IMAGE = 2;
+
 layout (location = 0) out vec4 frag_color;
 
 layout (set = 0, binding = 0) uniform Uniforms {
     Camera u_camera;
     vec2 window_size;
+    Fog fog;
+    DepthPalette depth_palette;
+    Lights lights;
+    Background background;
     VoxelType[256] types;
 };
 layout (set = 1, binding = 0) uniform utexture3D t_view;
 layout (set = 1, binding = 1) uniform sampler s_view;
+// How far the 4D ray travelled before hitting each view voxel.
+layout (set = 1, binding = 2) uniform texture3D t_view_depth;
+// The translucent voxels in front of each view voxel, as a premultiplied color.
+layout (set = 1, binding = 3) uniform texture3D t_view_tint;
+// An equirectangular panorama if the background is an image.
+layout (set = 2, binding = 0) uniform texture2D t_background;
+layout (set = 2, binding = 1) uniform sampler s_background;
 
 Ray generate_ray() {
     vec2 pixel_ndc = (gl_FragCoord.xy) / window_size;
@@ -46,13 +123,190 @@ bool contains_voxel(ivec3 location) {
     return texelFetch(usampler3D(t_view, s_view), location, 0).x != 1;
 }
 
+bool in_view(ivec3 location) {
+    return all(greaterThanEqual(location, ivec3(0))) && all(lessThan(location, textureSize(usampler3D(t_view, s_view), 0)));
+}
+
+vec4 get_tint(ivec3 location) {
+    return texelFetch(sampler3D(t_view_tint, s_view), location, 0);
+}
+
+float get_depth(ivec3 location) {
+    return texelFetch(sampler3D(t_view_depth, s_view), location, 0).x;
+}
+
+vec3 apply_fog(vec3 color, float depth) {
+    float t = clamp((depth - fog.near) / max(fog.far - fog.near, EPSILON), 0, 1);
+    return mix(color, fog.color, t * fog.strength);
+}
+
+vec3 depth_color(float depth) {
+    float t = clamp((depth - depth_palette.near) / max(depth_palette.far - depth_palette.near, EPSILON), 0, 1);
+    float position = t * (PALETTE_STOPS - 1);
+    int index = min(int(floor(position)), PALETTE_STOPS - 2);
+    return mix(depth_palette.stops[index].rgb, depth_palette.stops[index + 1].rgb, position - index);
+}
+
+// How much of the light travelling from `origin` along `direction` arrives, after passing
+// through translucent voxels, up to `max_distance` away.
+float trace_shadow(vec3 origin, vec3 direction, float max_distance) {
+    ivec3 voxel_pos = ivec3(floor(origin));
+    vec3 delta_dist = abs(vec3(1) / direction);
+    ivec3 ray_step = ivec3(sign(direction));
+    vec3 side_dist = (sign(direction) * (vec3(voxel_pos) - origin) + sign(direction) * 0.5 + 0.5) * delta_dist;
+
+    float transmittance = 1;
+
+    for (int i = 0; i < 128 * 3; i++) {
+        if (!in_view(voxel_pos)) break;
+        if (contains_voxel(voxel_pos)) return 0;
+        transmittance *= 1 - get_tint(voxel_pos).a;
+        if (transmittance <= 1 - MAX_ALPHA) return 0;
+        if (min(side_dist.x, min(side_dist.y, side_dist.z)) > max_distance) break;
+
+        bvec3 mask = lessThanEqual(side_dist.xyz, min(side_dist.yzx, side_dist.zxy));
+
+        side_dist += vec3(mask) * delta_dist;
+        voxel_pos += ivec3(mask) * ray_step;
+    }
+    return transmittance;
+}
+
+float occlusion(ivec3 location) {
+    return in_view(location) && contains_voxel(location) ? 1 : 0;
+}
+
+// Darkens faces by how many of the voxels around the one in front of them are filled, so that
+// corners get less ambient light. `normal` has to point along an axis.
+float ambient_occlusion(ivec3 location, ivec3 normal) {
+    ivec3 front = location + normal;
+    ivec3 u = abs(normal).yzx;
+    ivec3 v = abs(normal).zxy;
+    float edges = occlusion(front + u) + occlusion(front - u) + occlusion(front + v) + occlusion(front - v);
+    float corners = occlusion(front + u + v) + occlusion(front + u - v) + occlusion(front - u + v) + occlusion(front - u - v);
+    return 1 - 0.5 * (edges + 0.5 * corners) / 6;
+}
+
+void add_light(inout Lighting lighting, vec3 position, vec3 to_light, float max_distance, vec3 color, vec3 normal, vec3 to_camera, float roughness) {
+    float n_dot_l = max(dot(normal, to_light), 0);
+    if (lights.shadows != 0 && n_dot_l > 0) {
+        color *= trace_shadow(position + normal * SHADOW_BIAS, to_light, max_distance);
+    }
+    lighting.diffuse += color * n_dot_l;
+    vec3 halfway = normalize(to_light + to_camera);
+    float shininess = 2 / max(roughness * roughness, EPSILON) - 2;
+    // pow(0, 0) is undefined, and fully rough surfaces have no highlight anyway.
+    float highlight = roughness < 1 ? pow(max(dot(normal, halfway), 0), shininess) : 0;
+    lighting.specular += color * n_dot_l * (1 - roughness) * highlight;
+}
+
+// Lambert diffuse and Blinn-Phong specular lighting for a face.
+// `ao` scales the ambient light.
+Lighting light_face(vec3 position, vec3 normal, vec3 to_camera, float roughness, float ao) {
+    Lighting lighting;
+    lighting.diffuse = lights.ambient * ao;
+    lighting.specular = vec3(0);
+    add_light(lighting, position, lights.to_directional, MAX_FLOAT, lights.directional, normal, to_camera, roughness);
+    for (uint i = 0; i < lights.point_light_count; i++) {
+        PointLight light = lights.point_lights[i];
+        vec3 to_light = light.position - position;
+        float distance = length(to_light);
+        float attenuation = clamp(1 - distance / max(light.range, EPSILON), 0, 1);
+        add_light(lighting, position, to_light / max(distance, EPSILON), distance, light.color * attenuation * attenuation, normal, to_camera, roughness);
+    }
+    return lighting;
+}
+
+// The center of the face of the voxel at `location` facing along `normal`.
+vec3 face_center(ivec3 location, vec3 normal) {
+    return vec3(location) + 0.5 + 0.5 * normal;
+}
+
+// The depth palette already shows depth, so fog is only applied to type colors.
+vec3 shade_voxel(ivec3 location, vec3 normal, vec3 to_camera, float ao) {
+    VoxelType voxel = get_voxel(location);
+    float depth = get_depth(location);
+    vec3 albedo = depth_palette.mode == COLOR_DEPTH ? depth_color(depth) : voxel.color;
+    Lighting lighting = light_face(face_center(location, normal), normal, to_camera, voxel.roughness, ao);
+    vec3 color = albedo * (lighting.diffuse + voxel.emissive) + lighting.specular;
+    return depth_palette.mode == COLOR_DEPTH ? color : apply_fog(color, depth);
+}
+
+// The ambient occlusion of the face the ray entered the current voxel through, if it is enabled.
+float face_ambient_occlusion(ivec3 location, bvec3 mask, ivec3 ray_step) {
+    if (lights.ambient_occlusion == 0 || !any(mask)) {
+        return 1;
+    }
+    return ambient_occlusion(location, -ivec3(mask) * ray_step);
+}
+
+// The normal of the face the ray entered the current voxel through, or facing the camera if
+// the ray has not stepped yet.
+vec3 face_normal(bvec3 mask, ivec3 ray_step, vec3 direction) {
+    if (!any(mask)) {
+        return -direction;
+    }
+    return normalize(-vec3(mask) * vec3(ray_step));
+}
+
+vec3 background_color(vec3 direction) {
+    if (background.mode == BACKGROUND_IMAGE) {
+        // Z is up, so the elevation comes from Z and the azimuth from X and Y.
+        vec2 uv = vec2(atan(direction.y, direction.x) / (2 * PI) + 0.5, acos(clamp(direction.z, -1, 1)) / PI);
+        return textureLod(sampler2D(t_background, s_background), uv, 0).rgb;
+    }
+    if (background.mode == BACKGROUND_GRADIENT) {
+        return mix(background.bottom, background.top, direction.z * 0.5 + 0.5);
+    }
+    return background.top;
+}
+
+// The ray parameters where the ray enters and leaves the view volume. They are the wrong way
+// around if the ray misses it. `entry_face` is the axis of the face the ray enters through.
+vec2 intersect_view(Ray ray, out bvec3 entry_face) {
+    vec3 t0 = -ray.origin / ray.direction;
+    vec3 t1 = (vec3(textureSize(usampler3D(t_view, s_view), 0)) - ray.origin) / ray.direction;
+    vec3 t_min = min(t0, t1);
+    vec3 t_max = max(t0, t1);
+    float t_enter = max(t_min.x, max(t_min.y, t_min.z));
+    entry_face = equal(t_min, vec3(t_enter));
+    return vec2(t_enter, min(t_max.x, min(t_max.y, t_max.z)));
+}
+
+// Whether a point on the surface of the view volume is close to two of its faces.
+bool on_view_bounds(vec3 point) {
+    vec3 distance = min(point, vec3(textureSize(usampler3D(t_view, s_view), 0)) - point);
+    bvec3 near_face = lessThan(distance, vec3(background.bounds_width));
+    return int(near_face.x) + int(near_face.y) + int(near_face.z) >= 2;
+}
 
 // https://www.shadertoy.com/view/4dX3zl
 void main() {
     Ray ray = generate_ray();
 
+    bvec3 entry_face;
+    vec2 t_view_range = intersect_view(ray, entry_face);
+    if (t_view_range.x >= t_view_range.y || t_view_range.y < 0) {
+        frag_color = vec4(background_color(ray.direction), 1);
+        return;
+    }
+    vec3 exit_point = ray.origin + ray.direction * t_view_range.y;
+    // The front of the bounds covers everything inside the view volume.
+    if (t_view_range.x > 0 && on_view_bounds(ray.origin + ray.direction * t_view_range.x)) {
+        frag_color = vec4(background.bounds_color, 1);
+        return;
+    }
+
+    bvec3 mask = bvec3(false);
+
+    // Rays starting outside of the view volume start on its surface instead.
+    if (t_view_range.x > 0) {
+        mask = entry_face;
+        ray.origin += ray.direction * t_view_range.x;
+    }
+
     // Position of the voxel the ray is currently in (integer)
-    ivec3 voxel_pos = ivec3(floor(ray.origin));
+    ivec3 voxel_pos = clamp(ivec3(floor(ray.origin)), ivec3(0), textureSize(usampler3D(t_view, s_view), 0) - 1);
 
     // The amount you need to go along the ray to increment the voxel by one.
     vec3 delta_dist = abs(vec3(1) / ray.direction);
@@ -65,10 +319,19 @@ void main() {
     // The distance to the next voxel along all 3 directions.
     vec3 side_dist = (sign(ray.direction) * (vec3(voxel_pos) - ray.origin) + sign(ray.direction) * 0.5 + 0.5) * delta_dist;
 
-    bvec3 mask;
+    // Premultiplied color of the translucent view voxels passed through so far.
+    vec4 color = vec4(0);
 
     for (int i = 0; i < 128 * 3; i++) {
-        if (contains_voxel(voxel_pos)) break;
+        if (!in_view(voxel_pos)) break;
+        vec4 tint = get_tint(voxel_pos);
+        if (tint.a > 0) {
+            vec3 normal = face_normal(mask, ray_step, ray.direction);
+            float ao = face_ambient_occlusion(voxel_pos, mask, ray_step);
+            vec3 diffuse = light_face(face_center(voxel_pos, normal), normal, -ray.direction, 1, ao).diffuse;
+            color += (1 - color.a) * vec4(diffuse * tint.rgb, tint.a);
+        }
+        if (contains_voxel(voxel_pos) || color.a >= MAX_ALPHA) break;
 
         mask = lessThanEqual(side_dist.xyz, min(side_dist.yzx, side_dist.zxy));
 
@@ -76,18 +339,15 @@ void main() {
         voxel_pos += ivec3(mask) * ray_step;
     }
 
-    VoxelType voxel = get_voxel(voxel_pos);
-
-    float shadow;
-    if (mask.x) {
-        shadow = 0.5;
-    }
-    if (mask.y) {
-        shadow = 1.0;
+    vec3 behind;
+    if (in_view(voxel_pos) && contains_voxel(voxel_pos)) {
+        vec3 normal = face_normal(mask, ray_step, ray.direction);
+        float ao = face_ambient_occlusion(voxel_pos, mask, ray_step);
+        behind = shade_voxel(voxel_pos, normal, -ray.direction, ao);
+    } else if (on_view_bounds(exit_point)) {
+        behind = background.bounds_color;
+    } else {
+        behind = background_color(ray.direction);
     }
-    if (mask.z) {
-        shadow = 0.75;
-    }
-
-    frag_color = vec4(contains_voxel(voxel_pos) ? shadow * voxel.color : vec3(0), 1);
+    frag_color = vec4(color.rgb + (1 - color.a) * behind, 1);
 }
@@ -0,0 +1,323 @@
+use crate::surface::{DeviceResource, QueueResource};
+use crate::uniform_3d::Uniforms;
+use bevy::prelude::*;
+use bytemuck::{Pod, Zeroable};
+use nalgebra::{Vector2, Vector3};
+use palette::{LinSrgb, Srgb};
+use png::{ColorType, Decoder, Transformations};
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use wgpu::util::DeviceExt;
+use wgpu::*;
+
+const SOLID: u32 = 0;
+const GRADIENT: u32 = 1;
+const IMAGE: u32 = 2;
+
+/// What the 3D stage shows where a ray leaves the view volume without hitting anything.
+#[derive(Resource, Clone, Debug, PartialEq)]
+pub enum Background {
+    Solid(Srgb),
+    /// A vertical gradient, going from `bottom` when looking straight down to `top` when
+    /// looking straight up.
+    Gradient {
+        top: Srgb,
+        bottom: Srgb,
+    },
+    Image(BackgroundImage),
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Background::Gradient {
+            top: Srgb::new(0.118, 0.133, 0.165),
+            bottom: Srgb::new(0.020, 0.022, 0.027),
+        }
+    }
+}
+
+/// Parses a color written as `#rrggbb`.
+pub fn parse_hex_color(hex: &str) -> Option<Srgb> {
+    let hex = hex.strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    Some(Srgb::new(channel(0)?, channel(2)?, channel(4)?).into_format())
+}
+
+/// An equirectangular panorama, with the top row straight up along Z.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackgroundImage {
+    pub size: Vector2<u32>,
+    /// sRGB pixels, four bytes each, with the first row at the top.
+    pub pixels: Vec<u8>,
+}
+
+impl BackgroundImage {
+    /// Loads a PNG file, converting it to 8 bit RGBA.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<BackgroundImage> {
+        let mut decoder = Decoder::new(File::open(path)?);
+        decoder.set_transformations(Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info()?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer)?;
+        buffer.truncate(info.buffer_size());
+
+        let pixels = match info.color_type {
+            ColorType::Rgba => buffer,
+            ColorType::Rgb => buffer
+                .chunks(3)
+                .flat_map(|x| [x[0], x[1], x[2], 255])
+                .collect(),
+            ColorType::Grayscale => buffer.iter().flat_map(|&x| [x, x, x, 255]).collect(),
+            ColorType::GrayscaleAlpha => buffer
+                .chunks(2)
+                .flat_map(|x| [x[0], x[0], x[0], x[1]])
+                .collect(),
+            ColorType::Indexed => unreachable!("Palettes are expanded by the decoder"),
+        };
+        Ok(BackgroundImage {
+            size: Vector2::new(info.width, info.height),
+            pixels,
+        })
+    }
+
+    /// The pixel a direction maps to, like the nearest filtered lookup in `3d.frag`. Z is up,
+    /// so the elevation comes from Z and the azimuth from X and Y.
+    pub fn sample(&self, direction: Vector3<f32>) -> LinSrgb {
+        let uv = Vector2::new(
+            direction.y.atan2(direction.x) / (2.0 * PI) + 0.5,
+            direction.z.clamp(-1.0, 1.0).acos() / PI,
+        );
+        let x = ((uv.x * self.size.x as f32) as u32).min(self.size.x - 1) as usize;
+        let y = ((uv.y * self.size.y as f32) as u32).min(self.size.y - 1) as usize;
+        let i = (y * self.size.x as usize + x) * 4;
+        Srgb::new(self.pixels[i], self.pixels[i + 1], self.pixels[i + 2])
+            .into_format()
+            .into_linear()
+    }
+}
+
+/// The outline of the view volume, drawn so that it is clear where the projection ends.
+#[derive(Resource, Copy, Clone, Debug, PartialEq)]
+pub struct ViewBounds {
+    pub visible: bool,
+    pub color: Srgb,
+    /// Width of the outline, in view voxels.
+    pub width: f32,
+}
+
+impl Default for ViewBounds {
+    fn default() -> Self {
+        ViewBounds {
+            visible: true,
+            color: Srgb::new(0.4, 0.4, 0.4),
+            width: 0.25,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct BackgroundInternal {
+    pub mode: u32,
+    _padding: [u32; 3],
+    /// The solid color, or the top of the gradient.
+    pub top: LinSrgb,
+    _padding2: f32,
+    pub bottom: LinSrgb,
+    _padding3: f32,
+    pub bounds_color: LinSrgb,
+    /// Zero if the bounds are hidden.
+    pub bounds_width: f32,
+}
+
+impl Default for BackgroundInternal {
+    fn default() -> Self {
+        background_to_internal(&Background::default(), &ViewBounds::default())
+    }
+}
+
+unsafe impl Zeroable for BackgroundInternal {}
+unsafe impl Pod for BackgroundInternal {}
+
+pub fn background_to_internal(background: &Background, bounds: &ViewBounds) -> BackgroundInternal {
+    let black = Srgb::new(0.0, 0.0, 0.0);
+    let (mode, top, bottom) = match *background {
+        Background::Solid(color) => (SOLID, color, color),
+        Background::Gradient { top, bottom } => (GRADIENT, top, bottom),
+        Background::Image(_) => (IMAGE, black, black),
+    };
+    BackgroundInternal {
+        mode,
+        _padding: [0; 3],
+        top: top.into_linear(),
+        _padding2: 0.0,
+        bottom: bottom.into_linear(),
+        _padding3: 0.0,
+        bounds_color: bounds.color.into_linear(),
+        bounds_width: if bounds.visible { bounds.width } else { 0.0 },
+    }
+}
+
+impl BackgroundInternal {
+    /// The background in a direction, as in `3d.frag`. `background` is only used for images.
+    pub fn color(&self, background: &Background, direction: Vector3<f32>) -> LinSrgb {
+        match (self.mode, background) {
+            (IMAGE, Background::Image(image)) => image.sample(direction),
+            (GRADIENT, _) => {
+                let t = direction.z * 0.5 + 0.5;
+                self.bottom * (1.0 - t) + self.top * t
+            }
+            _ => self.top,
+        }
+    }
+}
+
+#[derive(Resource)]
+pub struct BackgroundBindGroup(pub BindGroup, pub BindGroupLayout);
+
+/// A texture holding the background image, or a single black pixel for the other backgrounds.
+fn create_bind_group(
+    device: &Device,
+    queue: &Queue,
+    layout: &BindGroupLayout,
+    background: &Background,
+) -> BindGroup {
+    let (size, pixels) = match background {
+        Background::Image(image) => (image.size, &image.pixels[..]),
+        _ => (Vector2::new(1, 1), &[0, 0, 0, 255][..]),
+    };
+    let texture = device.create_texture_with_data(
+        queue,
+        &TextureDescriptor {
+            label: Some("background-texture"),
+            size: Extent3d {
+                width: size.x,
+                height: size.y,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::Rgba8UnormSrgb,
+            usage: TextureUsages::TEXTURE_BINDING,
+        },
+        pixels,
+    );
+    let view = texture.create_view(&TextureViewDescriptor::default());
+    let sampler = device.create_sampler(&SamplerDescriptor {
+        mag_filter: FilterMode::Nearest,
+        min_filter: FilterMode::Nearest,
+        mipmap_filter: FilterMode::Nearest,
+        ..Default::default()
+    });
+    device.create_bind_group(&BindGroupDescriptor {
+        label: Some("background-bind-group"),
+        layout,
+        entries: &[
+            BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::TextureView(&view),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: BindingResource::Sampler(&sampler),
+            },
+        ],
+    })
+}
+
+pub fn init_background(
+    mut commands: Commands,
+    device: Res<DeviceResource>,
+    queue: Res<QueueResource>,
+    background: Res<Background>,
+) {
+    let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: Some("background-bind-group-layout"),
+        entries: &[
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: TextureViewDimension::D2,
+                    sample_type: TextureSampleType::Float { filterable: false },
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 1,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Sampler(SamplerBindingType::NonFiltering),
+                count: None,
+            },
+        ],
+    });
+    let bind_group = create_bind_group(&device, &queue, &bind_group_layout, &background);
+    commands.insert_resource(BackgroundBindGroup(bind_group, bind_group_layout));
+}
+
+pub fn view_bounds_system(key: Res<Input<KeyCode>>, mut bounds: ResMut<ViewBounds>) {
+    if key.just_pressed(KeyCode::B) {
+        bounds.visible = !bounds.visible;
+    }
+}
+
+pub fn update_background(
+    device: Res<DeviceResource>,
+    queue: Res<QueueResource>,
+    background: Res<Background>,
+    bounds: Res<ViewBounds>,
+    mut bind_group: ResMut<BackgroundBindGroup>,
+    mut uniforms: ResMut<Uniforms>,
+) {
+    if background.is_changed() {
+        bind_group.0 = create_bind_group(&device, &queue, &bind_group.1, &background);
+    }
+    if background.is_changed() || bounds.is_changed() {
+        uniforms.background = background_to_internal(&background, &bounds);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn z_is_up() {
+        // One column, with a red top row and a blue bottom row.
+        let image = BackgroundImage {
+            size: Vector2::new(1, 2),
+            pixels: vec![255, 0, 0, 255, 0, 0, 255, 255],
+        };
+        let red = LinSrgb::new(1.0, 0.0, 0.0);
+        let blue = LinSrgb::new(0.0, 0.0, 1.0);
+        assert_eq!(image.sample(Vector3::z()), red);
+        assert_eq!(image.sample(-Vector3::z()), blue);
+
+        let gradient = Background::Gradient {
+            top: Srgb::new(1.0, 0.0, 0.0),
+            bottom: Srgb::new(0.0, 0.0, 1.0),
+        };
+        let internal = background_to_internal(&gradient, &ViewBounds::default());
+        assert_eq!(internal.color(&gradient, Vector3::z()), red);
+        assert_eq!(internal.color(&gradient, -Vector3::z()), blue);
+        // Looking along the horizon is halfway between the two.
+        let side = internal.color(&gradient, Vector3::y());
+        assert_eq!(side, LinSrgb::new(0.5, 0.0, 0.5));
+    }
+
+    #[test]
+    fn parses_hex_colors() {
+        let color = parse_hex_color("#ff8000").unwrap();
+        assert_eq!(color.into_format::<u8>(), Srgb::new(255, 128, 0));
+        assert_eq!(parse_hex_color("ff8000"), None);
+        assert_eq!(parse_hex_color("#ff80"), None);
+        assert_eq!(parse_hex_color("#gg8000"), None);
+    }
+}
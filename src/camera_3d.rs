@@ -21,10 +21,10 @@ pub struct Camera {
 #[repr(C)]
 #[derive(Pod, Zeroable, Copy, Clone, Debug, Default)]
 pub struct CameraInternal {
-    position: Vector3<f32>,
+    pub position: Vector3<f32>,
     _padding: f32,
-    inv_rotation: Matrix4x3<f32>,
-    tan_half_fov: f32,
+    pub inv_rotation: Matrix4x3<f32>,
+    pub tan_half_fov: f32,
     _padding_2: [f32; 3],
 }
 
@@ -13,6 +13,130 @@ pub struct Camera {
     pub rotate_time: Duration,
     pub rotating: Option<Rotating>,
     pub rotation: Rotation4<f32>,
+    /// Speed of continuous rotations, in radians per second.
+    pub rotation_speed: f32,
+    /// Centre of the hyperplane the view rays start from, in world voxels. Uses the same
+    /// axis order as the shader, which is reversed relative to `World` indices.
+    pub position: Vector4<f32>,
+    /// Speed of movement, in voxels per second.
+    pub speed: f32,
+    pub projection: Projection,
+    /// Field of view of the perspective projection, in radians.
+    pub fov: f32,
+}
+
+/// How view rays are cast into the world. The discriminants match the constants in `4d.comp`.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Projection {
+    /// Parallel rays along the view W axis.
+    Orthographic = 0,
+    /// Rays spreading out from the camera position, so that distant objects are smaller.
+    Perspective = 1,
+    /// No rays at all: the view is the intersection of the world with the hyperplane through
+    /// the camera position that is perpendicular to the view W axis.
+    CrossSection = 2,
+}
+
+impl Projection {
+    pub fn next(self) -> Self {
+        match self {
+            Projection::Orthographic => Projection::Perspective,
+            Projection::Perspective => Projection::CrossSection,
+            Projection::CrossSection => Projection::Orthographic,
+        }
+    }
+}
+
+/// One of the six planes a 4D rotation can happen in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Plane {
+    XY,
+    XZ,
+    XW,
+    YZ,
+    YW,
+    ZW,
+}
+
+impl Plane {
+    fn axes(self) -> (usize, usize) {
+        match self {
+            Plane::XY => (0, 1),
+            Plane::XZ => (0, 2),
+            Plane::XW => (0, 3),
+            Plane::YZ => (1, 2),
+            Plane::YW => (1, 3),
+            Plane::ZW => (2, 3),
+        }
+    }
+
+    /// A rotation by `angle` radians in this plane, turning the first axis towards the second.
+    pub fn rotation(self, angle: f32) -> Rotation4<f32> {
+        let (a, b) = self.axes();
+        let mut rot = Matrix4::identity();
+        rot[(a, a)] = angle.cos();
+        rot[(a, b)] = -angle.sin();
+        rot[(b, a)] = angle.sin();
+        rot[(b, b)] = angle.cos();
+        Rotation4::from_matrix_unchecked(rot)
+    }
+}
+
+/// Keys that rotate the camera in each plane, in the positive and negative direction.
+const ROTATION_KEYS: [(Plane, KeyCode, KeyCode); 6] = [
+    (Plane::XY, KeyCode::Key1, KeyCode::Key2),
+    (Plane::XZ, KeyCode::Key3, KeyCode::Key4),
+    (Plane::XW, KeyCode::Key5, KeyCode::Key6),
+    (Plane::YZ, KeyCode::Key7, KeyCode::Key8),
+    (Plane::YW, KeyCode::Key9, KeyCode::Key0),
+    (Plane::ZW, KeyCode::Minus, KeyCode::Equals),
+];
+
+/// Gamepad axes that rotate the camera in each plane. The right stick turns the view direction
+/// towards the view X and Y axes.
+const GAMEPAD_ROTATION_AXES: [(Plane, GamepadAxisType); 2] = [
+    (Plane::XW, GamepadAxisType::RightStickX),
+    (Plane::YW, GamepadAxisType::RightStickY),
+];
+
+/// Keys that move the camera along each axis of its basis, in the positive and negative
+/// direction.
+const MOVE_KEYS: [(usize, KeyCode, KeyCode); 4] = [
+    (0, KeyCode::Right, KeyCode::Left),
+    (1, KeyCode::Up, KeyCode::Down),
+    (2, KeyCode::PageUp, KeyCode::PageDown),
+    (3, KeyCode::Home, KeyCode::End),
+];
+
+/// Gamepad axes that move the camera along each axis of its basis. The left stick moves it
+/// sideways and along the view direction.
+const GAMEPAD_MOVE_AXES: [(usize, GamepadAxisType); 2] = [
+    (0, GamepadAxisType::LeftStickX),
+    (3, GamepadAxisType::LeftStickY),
+];
+
+/// The position of an axis summed over every connected gamepad.
+fn gamepad_axis(gamepads: &Gamepads, axes: &Axis<GamepadAxis>, axis_type: GamepadAxisType) -> f32 {
+    gamepads
+        .iter()
+        .filter_map(|gamepad| axes.get(GamepadAxis::new(gamepad, axis_type)))
+        .sum()
+}
+
+/// Gram-Schmidt orthonormalizes the columns of a rotation, to stop accumulated floating point
+/// error from skewing it.
+fn orthonormalize(rotation: Rotation4<f32>) -> Rotation4<f32> {
+    let mut m = rotation.into_inner();
+    for i in 0..4 {
+        let mut column = m.column(i).into_owned();
+        for j in 0..i {
+            let previous = m.column(j).into_owned();
+            column -= previous * previous.dot(&column);
+        }
+        m.set_column(i, &column.normalize());
+    }
+    Rotation4::from_matrix_unchecked(m)
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -25,24 +149,16 @@ pub struct Rotating {
 #[repr(C)]
 #[derive(Pod, Zeroable, Copy, Clone, Debug, Default)]
 pub struct CameraInternal {
-    position: Vector4<f32>,
-    rotation: Matrix4<f32>,
-    voxel_size: f32,
-    _padding: [f32; 3],
-}
-
-fn print_mat4(m: Matrix4<f32>) {
-    #[rustfmt::skip]
-    println!("{} {} {} {}\n{} {} {} {}\n{} {} {} {}\n{} {} {} {}",
-        m[(0, 0)], m[(0, 1)], m[(0, 2)], m[(0, 3)],
-        m[(1, 0)], m[(1, 1)], m[(1, 2)], m[(1, 3)],
-        m[(2, 0)], m[(2, 1)], m[(2, 2)], m[(2, 3)],
-        m[(3, 0)], m[(3, 1)], m[(3, 2)], m[(3, 3)],
-    );
+    pub position: Vector4<f32>,
+    pub rotation: Matrix4<f32>,
+    pub voxel_size: f32,
+    pub projection: u32,
+    pub tan_half_fov: f32,
+    _padding: f32,
 }
 
 impl Camera {
-    pub fn new() -> Self {
+    pub fn new(world_size: WorldSize) -> Self {
         #[rustfmt::skip]
         let rotation = Matrix4::new(
             0.0, 1.0, 0.0, 0.0,
@@ -50,13 +166,38 @@ impl Camera {
             1.0, 0.0, 0.0, 0.0,
             0.0, 0.0, 1.0, 0.0,
         );
-        println!("{:?}", rotation);
-        print_mat4(rotation);
-        Camera {
+        let mut camera = Camera {
             rotate_time: Duration::from_secs(1),
             rotating: None,
             rotation: Rotation4::from_matrix_unchecked(rotation),
-        }
+            rotation_speed: 1.0,
+            position: Vector4::zeros(),
+            speed: 20.0,
+            projection: Projection::Orthographic,
+            fov: PI / 2.0,
+        };
+        camera.recenter(world_size);
+        camera
+    }
+
+    /// Moves the camera to the centre of the world, then back along the view direction
+    /// until it is at the edge of the world, so that the whole world is in front of it.
+    pub fn recenter(&mut self, world_size: WorldSize) {
+        let size = world_size.0 as f32;
+        let direction = self.rotation * Vector4::w();
+        self.position = Vector4::repeat(size / 2.0) - direction * size / 2.0;
+        self.clamp_position(world_size);
+    }
+
+    /// Moves the camera by `delta`, given in the camera's own basis, keeping it in the world.
+    pub fn translate(&mut self, delta: Vector4<f32>, world_size: WorldSize) {
+        self.position += self.rotation * delta;
+        self.clamp_position(world_size);
+    }
+
+    fn clamp_position(&mut self, world_size: WorldSize) {
+        let size = world_size.0 as f32;
+        self.position = self.position.map(|x| x.clamp(0.0, size));
     }
 
     fn rotate(&mut self, f: fn(f32) -> Rotation4<f32>) -> bool {
@@ -71,14 +212,22 @@ impl Camera {
         return true;
     }
 
-    fn to_internal(&self, world_size: WorldSize) -> CameraInternal {
+    /// Rotates the view by `angle` radians in a plane of the camera's own basis.
+    pub fn rotate_view(&mut self, plane: Plane, angle: f32) {
+        self.rotation = orthonormalize(self.rotation * plane.rotation(angle));
+    }
+
+    pub fn to_internal(&self) -> CameraInternal {
         let rotation = *self.rotation.matrix();
         CameraInternal {
-            position: Vector4::repeat(world_size.0 as f32 / 2.0 + 1.0)
-                - rotation * Vector4::new(0.0, 0.0, 0.0, 1.0) * world_size.0 as f32 * 2.0,
+            // Offset by the solid air border.
+            position: self.position + Vector4::repeat(1.0),
             rotation,
-            voxel_size: 1.0, // TODO
-            _padding: [0.0; 3],
+            // The orthographic and cross-section views show the world at its own scale.
+            voxel_size: 1.0,
+            projection: self.projection as u32,
+            tan_half_fov: (self.fov / 2.0).tan(),
+            _padding: 0.0,
         }
     }
 }
@@ -141,14 +290,12 @@ impl CameraPlugin {
             return;
         }
         if key.just_pressed(KeyCode::Q) {
-            println!("Q");
             if key.pressed(KeyCode::LShift) {
                 camera.rotate(r1_inv);
             } else {
                 camera.rotate(r1);
             }
         } else if key.just_pressed(KeyCode::E) {
-            println!("E");
             if key.pressed(KeyCode::LShift) {
                 camera.rotate(r2_inv);
             } else {
@@ -156,6 +303,60 @@ impl CameraPlugin {
             }
         }
     }
+    fn projection_system(key: Res<Input<KeyCode>>, mut camera: ResMut<Camera>) {
+        if key.just_pressed(KeyCode::Tab) {
+            camera.projection = camera.projection.next();
+        }
+    }
+    fn rotate_view_system(
+        time: Res<Time>,
+        key: Res<Input<KeyCode>>,
+        gamepads: Res<Gamepads>,
+        axes: Res<Axis<GamepadAxis>>,
+        mut camera: ResMut<Camera>,
+    ) {
+        if camera.rotating.is_some() {
+            return;
+        }
+        let keys = ROTATION_KEYS.map(|(plane, positive, negative)| {
+            let axis = key.pressed(positive) as i32 - key.pressed(negative) as i32;
+            (plane, axis as f32)
+        });
+        let sticks = GAMEPAD_ROTATION_AXES
+            .map(|(plane, axis_type)| (plane, gamepad_axis(&gamepads, &axes, axis_type)));
+        for (plane, axis) in keys.into_iter().chain(sticks) {
+            if axis != 0.0 {
+                let angle = axis * camera.rotation_speed * time.delta_seconds();
+                camera.rotate_view(plane, angle);
+            }
+        }
+    }
+    fn move_system(
+        time: Res<Time>,
+        key: Res<Input<KeyCode>>,
+        gamepads: Res<Gamepads>,
+        axes: Res<Axis<GamepadAxis>>,
+        world_size: Res<WorldSize>,
+        mut camera: ResMut<Camera>,
+    ) {
+        if key.just_pressed(KeyCode::R) {
+            camera.recenter(*world_size);
+        }
+        let mut delta = Vector4::<f32>::zeros();
+        for (axis, positive, negative) in MOVE_KEYS {
+            delta[axis] +=
+                key.pressed(positive) as i32 as f32 - key.pressed(negative) as i32 as f32;
+        }
+        for (axis, axis_type) in GAMEPAD_MOVE_AXES {
+            delta[axis] += gamepad_axis(&gamepads, &axes, axis_type);
+        }
+        if delta != Vector4::zeros() {
+            // Keeps the speed of diagonal movement, but lets a stick move slower.
+            delta = delta.cap_magnitude(1.0);
+            delta *= time.delta_seconds() * camera.speed;
+            camera.translate(delta, *world_size);
+        }
+    }
     fn rotating_system(mut camera: ResMut<Camera>) {
         if let Some(rotating) = camera.rotating {
             let now = Instant::now();
@@ -164,29 +365,24 @@ impl CameraPlugin {
                 .min(1.0);
             camera.rotation = (rotating.interpolate)(t) * rotating.last_rotation;
             if t == 1.0 {
-                print_mat4(*camera.rotation.matrix());
-                println!("X -> {:?}", camera.rotation * Vector4::x());
-                println!("Y -> {:?}", camera.rotation * Vector4::y());
-                println!("Z -> {:?}", camera.rotation * Vector4::z());
-                println!("W -> {:?}", camera.rotation * Vector4::w());
+                camera.rotation = orthonormalize(camera.rotation);
                 camera.rotating = None;
             }
         }
     }
-    fn update_uniform_system(
-        world_size: Res<WorldSize>,
-        camera: Res<Camera>,
-        mut uniforms: ResMut<Uniforms>,
-    ) {
+    fn update_uniform_system(camera: Res<Camera>, mut uniforms: ResMut<Uniforms>) {
         if camera.is_changed() {
-            uniforms.camera = camera.to_internal(*world_size);
+            uniforms.camera = camera.to_internal();
         }
     }
 }
 
 #[derive(SystemLabel, Clone, Hash, Debug, Eq, PartialEq)]
 enum Labels {
+    Projection,
     Rotate,
+    RotateView,
+    Move,
     Rotating,
     UpdateUniform,
 }
@@ -196,7 +392,18 @@ impl Plugin for CameraPlugin {
         app.add_system_set(
             SystemSet::new()
                 .label("camera-4d")
+                .with_system(Self::projection_system.label(Labels::Projection))
                 .with_system(Self::rotate_system.label(Labels::Rotate))
+                .with_system(
+                    Self::rotate_view_system
+                        .label(Labels::RotateView)
+                        .after(Labels::Rotate),
+                )
+                .with_system(
+                    Self::move_system
+                        .label(Labels::Move)
+                        .after(Labels::RotateView),
+                )
                 .with_system(
                     Self::rotating_system
                         .label(Labels::Rotating)
@@ -205,8 +412,63 @@ impl Plugin for CameraPlugin {
                 .with_system(
                     Self::update_uniform_system
                         .label(Labels::UpdateUniform)
+                        .after(Labels::Projection)
+                        .after(Labels::Move)
                         .after(Labels::Rotating),
                 ),
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLANES: [Plane; 6] = [
+        Plane::XY,
+        Plane::XZ,
+        Plane::XW,
+        Plane::YZ,
+        Plane::YW,
+        Plane::ZW,
+    ];
+
+    fn assert_orthonormal(rotation: Rotation4<f32>) {
+        let m = rotation.matrix();
+        let error = (m.transpose() * m - Matrix4::identity()).abs().max();
+        assert!(error < 1e-5, "{} off from orthonormal", error);
+    }
+
+    #[test]
+    fn rotating_the_view_stays_orthonormal() {
+        const STEPS: usize = 1000;
+        let mut camera = Camera::new(WorldSize(8));
+        let start = camera.rotation;
+        for plane in PLANES {
+            // A full turn in small steps, like holding a key for a few seconds.
+            for _ in 0..STEPS {
+                camera.rotate_view(plane, 2.0 * PI / STEPS as f32);
+                assert_orthonormal(camera.rotation);
+            }
+            let drift = (camera.rotation.matrix() - start.matrix()).abs().max();
+            assert!(drift < 1e-3, "{:?} drifted by {}", plane, drift);
+        }
+    }
+
+    #[test]
+    fn translating_stays_in_the_world() {
+        let size = WorldSize(8);
+        let mut camera = Camera::new(size);
+        camera.translate(Vector4::repeat(-100.0), size);
+        assert_eq!(camera.position, Vector4::zeros());
+        camera.translate(Vector4::repeat(100.0), size);
+        assert_eq!(camera.position, Vector4::repeat(8.0));
+
+        let delta = Vector4::new(-1.5, 0.0, 0.0, 0.0);
+        camera.translate(delta, size);
+        assert_eq!(
+            camera.position,
+            Vector4::repeat(8.0) + camera.rotation * delta
+        );
+    }
+}
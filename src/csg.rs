@@ -0,0 +1,294 @@
+//! Combining the voxels of a [`World`] with another world or a shape.
+//!
+//! A voxel counts as filled if it is anything other than air.
+
+use crate::region::Region;
+use crate::shapes::{voxel_center, Shape};
+use crate::voxel::{VoxelId, VoxelType};
+use crate::world::{TooManyTypes, World};
+use nalgebra::Vector4;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Operation {
+    /// Filled where either is filled.
+    Union,
+    /// Filled where both are filled.
+    Intersection,
+    /// Filled where the target is filled and the operand isn't.
+    Difference,
+    /// Filled where exactly one of them is filled.
+    Xor,
+    /// Filled where the target is filled, so that with [`Priority::Operand`] the operand only
+    /// recolors voxels and never fills air.
+    Paint,
+}
+
+/// Whose type a voxel gets where both the target and the operand are filled.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Priority {
+    Target,
+    Operand,
+}
+
+/// What the target is combined with.
+#[derive(Copy, Clone)]
+pub enum Operand<'a> {
+    /// Voxels at the same positions in another world, which is air where it is smaller than
+    /// the target.
+    World(&'a World),
+    /// The voxels with their center in the shape, filled with a type of the target.
+    Shape(&'a dyn Shape, VoxelId),
+}
+
+impl<'a> Operand<'a> {
+    /// The voxel at a position, with the types of a world mapped by `types`.
+    fn get(&self, position: Vector4<u32>, types: &[VoxelId; 256]) -> VoxelId {
+        match *self {
+            Operand::World(world) => {
+                if world.bounds().contains(position) {
+                    types[world[position].0 as usize]
+                } else {
+                    World::air()
+                }
+            }
+            Operand::Shape(shape, id) => {
+                if shape.contains(voxel_center(position)) {
+                    id
+                } else {
+                    World::air()
+                }
+            }
+        }
+    }
+}
+
+/// The types `source` uses in the region that `target` doesn't have yet, without duplicates.
+fn missing_types(target: &World, source: &World, region: Region) -> Vec<VoxelType> {
+    let mut used = [false; 256];
+    for position in region.intersection(&source.bounds()).positions() {
+        used[source[position].0 as usize] = true;
+    }
+    let mut missing: Vec<VoxelType> = Vec::new();
+    for (id, ty) in source.types().iter().enumerate().skip(2) {
+        if used[id] && target.find_type(ty).is_none() && !missing.contains(ty) {
+            missing.push(*ty);
+        }
+    }
+    missing
+}
+
+/// Maps the types of `source` to the equal types of `target`, or to air where it has none.
+fn map_types(target: &World, source: &World) -> [VoxelId; 256] {
+    let mut types = [World::air(); 256];
+    types[1] = World::solid_air();
+    for (id, ty) in source.types().iter().enumerate().skip(2) {
+        types[id] = target.find_type(ty).unwrap_or_else(World::air);
+    }
+    types
+}
+
+fn combine(target: VoxelId, operand: VoxelId, operation: Operation, priority: Priority) -> VoxelId {
+    let air = World::air();
+    match (target != air, operand != air) {
+        (true, true) => match operation {
+            Operation::Union | Operation::Intersection | Operation::Paint => match priority {
+                Priority::Target => target,
+                Priority::Operand => operand,
+            },
+            Operation::Difference | Operation::Xor => air,
+        },
+        (true, false) => match operation {
+            Operation::Intersection => air,
+            _ => target,
+        },
+        (false, true) => match operation {
+            Operation::Union | Operation::Xor => operand,
+            _ => air,
+        },
+        (false, false) => air,
+    }
+}
+
+/// Adds the types a world operand uses in the region to `target`, reusing equal types.
+/// Returns the ids of the inserted types, and inserts none if they don't all fit.
+pub fn insert_types(
+    target: &mut World,
+    region: Region,
+    operand: Operand,
+) -> Result<Vec<(VoxelId, VoxelType)>, TooManyTypes> {
+    let missing = match operand {
+        Operand::World(source) => missing_types(target, source, region),
+        Operand::Shape(..) => return Ok(Vec::new()),
+    };
+    if target.types().len() + missing.len() > 256 {
+        return Err(TooManyTypes);
+    }
+    missing
+        .into_iter()
+        .map(|ty| Ok((target.try_insert_type(ty)?, ty)))
+        .collect()
+}
+
+/// The voxels of `target` in the region combined with `operand`, in the order of
+/// [`Region::positions`]. The region should be within the bounds of `target`, and the types of
+/// a world operand should have been added with [`insert_types`].
+pub fn combine_region(
+    target: &World,
+    region: Region,
+    operand: Operand,
+    operation: Operation,
+    priority: Priority,
+) -> Vec<VoxelId> {
+    let types = match operand {
+        Operand::World(source) => map_types(target, source),
+        Operand::Shape(..) => [World::air(); 256],
+    };
+    region
+        .positions()
+        .map(|position| {
+            combine(
+                target[position],
+                operand.get(position, &types),
+                operation,
+                priority,
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shapes::draw;
+    use crate::voxel::test_types;
+
+    fn cube(min: u32, max: u32) -> Region {
+        Region::new(Vector4::repeat(min), Vector4::repeat(max))
+    }
+
+    fn apply(target: &mut World, operand: Operand, operation: Operation, priority: Priority) {
+        let region = target.bounds();
+        insert_types(target, region, operand).unwrap();
+        let voxels = combine_region(target, region, operand, operation, priority);
+        target.write_region(region, voxels);
+    }
+
+    /// The number of voxels of each type, indexed by id.
+    fn counts(world: &World) -> Vec<usize> {
+        let mut counts = vec![0; world.types().len()];
+        for position in world.positions() {
+            counts[world[position].0 as usize] += 1;
+        }
+        counts
+    }
+
+    /// Combines a cube of stone with an overlapping cube of glass. They have 4⁴ voxels each and
+    /// 2⁴ of them in common.
+    fn combine_cubes(operation: Operation, priority: Priority) -> Vec<usize> {
+        let mut world = World::new(8);
+        let stone = world.insert_type(test_types::stone());
+        let glass = world.insert_type(test_types::glass());
+        draw(&mut world, &cube(0, 4), stone);
+        apply(
+            &mut world,
+            Operand::Shape(&cube(2, 6), glass),
+            operation,
+            priority,
+        );
+        counts(&world)[2..].to_vec()
+    }
+
+    #[test]
+    fn union() {
+        assert_eq!(
+            combine_cubes(Operation::Union, Priority::Target),
+            [256, 240]
+        );
+        assert_eq!(
+            combine_cubes(Operation::Union, Priority::Operand),
+            [240, 256]
+        );
+    }
+
+    #[test]
+    fn intersection() {
+        assert_eq!(
+            combine_cubes(Operation::Intersection, Priority::Target),
+            [16, 0]
+        );
+        assert_eq!(
+            combine_cubes(Operation::Intersection, Priority::Operand),
+            [0, 16]
+        );
+    }
+
+    #[test]
+    fn difference() {
+        assert_eq!(
+            combine_cubes(Operation::Difference, Priority::Target),
+            [240, 0]
+        );
+    }
+
+    #[test]
+    fn xor() {
+        assert_eq!(combine_cubes(Operation::Xor, Priority::Target), [240, 240]);
+    }
+
+    #[test]
+    fn paint() {
+        assert_eq!(combine_cubes(Operation::Paint, Priority::Target), [256, 0]);
+        assert_eq!(
+            combine_cubes(Operation::Paint, Priority::Operand),
+            [240, 16]
+        );
+    }
+
+    #[test]
+    fn world_operands_reuse_equal_types() {
+        let mut target = World::new(8);
+        let stone = target.insert_type(test_types::stone());
+        draw(&mut target, &cube(0, 4), stone);
+        let mut source = World::new(4);
+        // Two copies of the same new type, and one the target already has.
+        let glass = source.insert_type(test_types::glass());
+        let glass_copy = source.insert_type(test_types::glass());
+        let source_stone = source.insert_type(test_types::stone());
+        draw(&mut source, &cube(0, 1), glass);
+        draw(&mut source, &cube(1, 2), glass_copy);
+        draw(&mut source, &cube(3, 4), source_stone);
+
+        apply(
+            &mut target,
+            Operand::World(&source),
+            Operation::Union,
+            Priority::Operand,
+        );
+        assert_eq!(
+            target.types()[2..],
+            [test_types::stone(), test_types::glass()]
+        );
+        assert_eq!(target[Vector4::repeat(0)], VoxelId(3));
+        assert_eq!(target[Vector4::repeat(1)], VoxelId(3));
+        assert_eq!(counts(&target)[2..], [254, 2]);
+    }
+
+    #[test]
+    fn inserting_too_many_types_is_an_error() {
+        let mut target = World::new(4);
+        for i in 2..255 {
+            target.insert_type(test_types::filler(i));
+        }
+        let mut source = World::new(4);
+        let stone = source.insert_type(test_types::stone());
+        let glass = source.insert_type(test_types::glass());
+        source.set(Vector4::repeat(0), stone);
+        source.set(Vector4::repeat(1), glass);
+        let region = target.bounds();
+        assert_eq!(
+            insert_types(&mut target, region, Operand::World(&source)),
+            Err(TooManyTypes)
+        );
+        assert_eq!(target.types().len(), 255);
+    }
+}
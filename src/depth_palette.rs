@@ -0,0 +1,114 @@
+use crate::uniform_3d::Uniforms;
+use bevy::prelude::*;
+use bytemuck::{Pod, Zeroable};
+use palette::{Gradient, LinSrgb, Srgb};
+
+/// Number of evenly spaced colors the gradient is baked into for the 3D stage.
+pub const PALETTE_STOPS: usize = 32;
+
+/// What the 3D stage colors voxels by.
+#[repr(u32)]
+#[derive(Resource, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ColorMode {
+    /// The color of the voxel's [`crate::voxel::VoxelType`].
+    Type = 0,
+    /// The [`DepthPalette`] color at how far the 4D ray travelled before hitting the voxel.
+    /// Voxels from different structures that project onto the same spot then stand apart.
+    Depth = 1,
+}
+
+impl ColorMode {
+    pub fn next(self) -> Self {
+        match self {
+            ColorMode::Type => ColorMode::Depth,
+            ColorMode::Depth => ColorMode::Type,
+        }
+    }
+}
+
+/// Colors used in [`ColorMode::Depth`]. The start of the gradient is used at `near` and the end
+/// at `far`, both in world voxels.
+#[derive(Resource, Clone, Debug)]
+pub struct DepthPalette {
+    pub gradient: Gradient<LinSrgb>,
+    pub near: f32,
+    pub far: f32,
+}
+
+impl Default for DepthPalette {
+    fn default() -> Self {
+        let colors = [
+            Srgb::new(0.267, 0.005, 0.329),
+            Srgb::new(0.230, 0.322, 0.546),
+            Srgb::new(0.128, 0.567, 0.551),
+            Srgb::new(0.369, 0.789, 0.383),
+            Srgb::new(0.993, 0.906, 0.144),
+        ];
+        DepthPalette {
+            gradient: Gradient::new(colors.iter().map(|color| color.into_linear())),
+            near: 0.0,
+            far: 128.0,
+        }
+    }
+}
+
+impl DepthPalette {
+    pub fn to_internal(&self, mode: ColorMode) -> DepthPaletteInternal {
+        let mut stops = [[0.0; 4]; PALETTE_STOPS];
+        for (stop, color) in stops.iter_mut().zip(self.gradient.take(PALETTE_STOPS)) {
+            *stop = [color.red, color.green, color.blue, 0.0];
+        }
+        DepthPaletteInternal {
+            mode: mode as u32,
+            near: self.near,
+            far: self.far,
+            _padding: 0.0,
+            stops,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Pod, Zeroable, Copy, Clone, Debug)]
+pub struct DepthPaletteInternal {
+    pub mode: u32,
+    pub near: f32,
+    pub far: f32,
+    _padding: f32,
+    pub stops: [[f32; 4]; PALETTE_STOPS],
+}
+
+impl Default for DepthPaletteInternal {
+    fn default() -> Self {
+        DepthPalette::default().to_internal(ColorMode::Type)
+    }
+}
+
+impl DepthPaletteInternal {
+    /// The color at `depth`, interpolating between the stops like `3d.frag`.
+    pub fn color(&self, depth: f32) -> LinSrgb {
+        let t = ((depth - self.near) / (self.far - self.near).max(f32::EPSILON)).clamp(0.0, 1.0);
+        let position = t * (PALETTE_STOPS - 1) as f32;
+        let index = (position.floor() as usize).min(PALETTE_STOPS - 2);
+        let fraction = position - index as f32;
+        let [r0, g0, b0, _] = self.stops[index];
+        let [r1, g1, b1, _] = self.stops[index + 1];
+        LinSrgb::new(r0, g0, b0) * (1.0 - fraction) + LinSrgb::new(r1, g1, b1) * fraction
+    }
+}
+
+pub fn color_mode_system(key: Res<Input<KeyCode>>, mut mode: ResMut<ColorMode>) {
+    if key.just_pressed(KeyCode::C) {
+        *mode = mode.next();
+    }
+}
+
+pub fn update_uniform_system(
+    palette: Res<DepthPalette>,
+    mode: Res<ColorMode>,
+    mut uniforms: ResMut<Uniforms>,
+) {
+    if palette.is_changed() || mode.is_changed() {
+        uniforms.depth_palette = palette.to_internal(*mode);
+    }
+}
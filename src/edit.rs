@@ -0,0 +1,221 @@
+use crate::camera_3d;
+use crate::csg::{Operand, Operation, Priority};
+use crate::history::History;
+use crate::picking::PickedVoxel;
+use crate::region::Region;
+use crate::shapes::{
+    voxel_center, Cylinder, Duocylinder, HalfSpace, Hypersphere, Shape, Simplex, TesseractShell,
+};
+use crate::voxel::VoxelId;
+use crate::world::World;
+use bevy::prelude::*;
+use nalgebra::{Vector2, Vector4};
+
+/// The first id after air and solid air, which is the first type a world can use.
+const FIRST_TYPE: u8 = 2;
+
+/// The voxel type placed and painted by the editing tools.
+#[derive(Resource, Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SelectedType(pub VoxelId);
+
+impl Default for SelectedType {
+    fn default() -> Self {
+        SelectedType(VoxelId(FIRST_TYPE))
+    }
+}
+
+/// Cycles through the types of the world with ] and [.
+pub fn select_type_system(
+    key: Res<Input<KeyCode>>,
+    world: Res<World>,
+    mut selected: ResMut<SelectedType>,
+) {
+    let count = world.types().len() as u8;
+    if count <= FIRST_TYPE {
+        return;
+    }
+    let index = selected.0 .0.clamp(FIRST_TYPE, count - 1) - FIRST_TYPE;
+    let types = count - FIRST_TYPE;
+    if key.just_pressed(KeyCode::RBracket) {
+        selected.0 = VoxelId(FIRST_TYPE + (index + 1) % types);
+    }
+    if key.just_pressed(KeyCode::LBracket) {
+        selected.0 = VoxelId(FIRST_TYPE + (index + types - 1) % types);
+    }
+}
+
+/// The shape the editing tools apply.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BrushShape {
+    /// A tesseract `2 * radius - 1` voxels wide, which is a single voxel at radius 1.
+    Cube,
+    Hypersphere,
+    /// A tesseract one voxel thick.
+    TesseractShell,
+    /// Extruded along the axis of the face the brush is placed against.
+    Cylinder,
+    Duocylinder,
+    Simplex,
+    /// Everything behind the face the brush is placed against, regardless of the radius.
+    HalfSpace,
+}
+
+impl BrushShape {
+    pub fn next(self) -> Self {
+        match self {
+            BrushShape::Cube => BrushShape::Hypersphere,
+            BrushShape::Hypersphere => BrushShape::TesseractShell,
+            BrushShape::TesseractShell => BrushShape::Cylinder,
+            BrushShape::Cylinder => BrushShape::Duocylinder,
+            BrushShape::Duocylinder => BrushShape::Simplex,
+            BrushShape::Simplex => BrushShape::HalfSpace,
+            BrushShape::HalfSpace => BrushShape::Cube,
+        }
+    }
+}
+
+/// The shape and size of the editing tools.
+#[derive(Resource, Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Brush {
+    pub shape: BrushShape,
+    /// In voxels, from 1 to [`MAX_BRUSH_RADIUS`].
+    pub radius: u32,
+}
+
+impl Default for Brush {
+    fn default() -> Self {
+        Brush {
+            shape: BrushShape::Cube,
+            radius: 1,
+        }
+    }
+}
+
+pub const MAX_BRUSH_RADIUS: u32 = 16;
+
+impl Brush {
+    /// The brush centered on the voxel at `position`, placed against the face of a voxel
+    /// with the given normal.
+    pub fn shape(&self, position: Vector4<u32>, normal: Vector4<i32>) -> Box<dyn Shape> {
+        let center = voxel_center(position);
+        // Keeps a radius of 1 to the center voxel.
+        let radius = self.radius as f32 - 0.5;
+        match self.shape {
+            BrushShape::Cube => Box::new(Region::new(
+                position.map(|x| x.saturating_sub(self.radius - 1)),
+                position.map(|x| x + self.radius),
+            )),
+            BrushShape::Hypersphere => Box::new(Hypersphere { center, radius }),
+            BrushShape::TesseractShell => Box::new(TesseractShell {
+                center,
+                half_size: radius,
+                thickness: 1.0,
+            }),
+            BrushShape::Cylinder => {
+                let axis = normal.iamax();
+                Box::new(Cylinder::new(center, axis, radius, radius))
+            }
+            BrushShape::Duocylinder => Box::new(Duocylinder {
+                center,
+                radii: Vector2::repeat(radius),
+            }),
+            BrushShape::Simplex => Box::new(Simplex::regular(center, radius)),
+            BrushShape::HalfSpace => Box::new(HalfSpace {
+                point: center,
+                normal: normal.cast(),
+            }),
+        }
+    }
+}
+
+/// Cycles through the brush shapes with N, and shrinks and grows the brush with , and .
+pub fn brush_system(key: Res<Input<KeyCode>>, mut brush: ResMut<Brush>) {
+    if key.just_pressed(KeyCode::N) {
+        brush.shape = brush.shape.next();
+    }
+    if key.just_pressed(KeyCode::Comma) && brush.radius > 1 {
+        brush.radius -= 1;
+    }
+    if key.just_pressed(KeyCode::Period) && brush.radius < MAX_BRUSH_RADIUS {
+        brush.radius += 1;
+    }
+}
+
+/// Edits the world with the brush at the voxel at the center of the screen while the 3D camera
+/// has the cursor. The left button removes the voxels in the brush, the right button fills
+/// the air in it with the selected type against the face that is seen, and the middle button
+/// paints the voxels in it that aren't air with the selected type.
+pub fn edit_system(
+    btn: Res<Input<MouseButton>>,
+    camera: Res<camera_3d::Camera>,
+    picked: Res<PickedVoxel>,
+    selected: Res<SelectedType>,
+    brush: Res<Brush>,
+    mut history: ResMut<History>,
+    mut world: ResMut<World>,
+) {
+    let hit = match picked.0 {
+        Some(pick) if camera.active => pick.world,
+        _ => return,
+    };
+    let (position, operation, priority) = if btn.just_pressed(MouseButton::Left) {
+        (hit.position, Operation::Difference, Priority::Target)
+    } else if btn.just_pressed(MouseButton::Right) {
+        let position = hit.position.cast::<i64>() + hit.normal.cast();
+        let size = world.size() as i64;
+        if !position.iter().all(|&x| (0..size).contains(&x)) {
+            return;
+        }
+        (
+            position.map(|x| x as u32),
+            Operation::Union,
+            Priority::Target,
+        )
+    } else if btn.just_pressed(MouseButton::Middle) {
+        (hit.position, Operation::Paint, Priority::Operand)
+    } else {
+        return;
+    };
+    let selected = selected.0;
+    if selected.0 as usize >= world.types().len() {
+        return;
+    }
+
+    let shape = brush.shape(position, hit.normal);
+    let region = shape.region(world.size());
+    let operand = Operand::Shape(&*shape, selected);
+    // Only marks the world as changed when something changes, so that `update_world` only
+    // uploads actual edits. Shapes never need new types, so this can't fail.
+    let changed = history.apply(
+        world.bypass_change_detection(),
+        region,
+        operand,
+        operation,
+        priority,
+    );
+    if changed == Ok(true) {
+        world.set_changed();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn brushes_of_radius_one_cover_one_voxel() {
+        let position = Vector4::new(3, 4, 5, 6);
+        let normal = Vector4::new(0, -1, 0, 0);
+        let mut brush = Brush::default();
+        while brush.shape != BrushShape::HalfSpace {
+            let shape = brush.shape(position, normal);
+            let voxels: Vec<_> = shape
+                .region(16)
+                .positions()
+                .filter(|&x| shape.contains(voxel_center(x)))
+                .collect();
+            assert_eq!(voxels, [position], "{:?}", brush.shape);
+            brush.shape = brush.shape.next();
+        }
+    }
+}
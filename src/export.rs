@@ -0,0 +1,443 @@
+//! Exporting the 3D view volume as a MagicaVoxel `.vox` model or as a mesh.
+//!
+//! Only the opaque voxels of the view are exported, as translucent voxels are blended into
+//! the tint of the voxels behind them by `4d.comp`. View voxel `(x, y, z)` is exported at
+//! `(x, y, z)`, which keeps Z up.
+
+use crate::surface::{DeviceResource, QueueResource};
+use crate::view::ViewTexture;
+use crate::voxel::{VoxelId, VoxelType};
+use crate::world::World;
+use bevy::prelude::*;
+use byteorder::{LittleEndian, WriteBytesExt};
+use nalgebra::Vector3;
+use ndarray::Array3;
+use palette::Srgb;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Vox,
+    /// Quads with the color of their voxel type as vertex colors.
+    Obj,
+    /// Binary STL, with the color of each triangle in its attribute bytes as VisCAM and
+    /// SolidView do.
+    Stl,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Vox => "vox",
+            Format::Obj => "obj",
+            Format::Stl => "stl",
+        }
+    }
+
+    pub fn from_path(path: impl AsRef<Path>) -> io::Result<Format> {
+        let extension = path.as_ref().extension().and_then(|x| x.to_str());
+        [Format::Vox, Format::Obj, Format::Stl]
+            .into_iter()
+            .find(|x| Some(x.extension()) == extension)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Unknown export format"))
+    }
+}
+
+/// Whether a view voxel is exported. Air is outside of the view and solid air is empty.
+fn is_solid(id: VoxelId) -> bool {
+    id.0 > 1
+}
+
+/// Writes the view voxels to a file, with `types` being the types of the world.
+pub fn export(
+    path: impl AsRef<Path>,
+    format: Format,
+    voxels: &Array3<VoxelId>,
+    types: &[VoxelType],
+) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    match format {
+        Format::Vox => write_vox(&mut writer, voxels, types)?,
+        Format::Obj => write_obj(&mut writer, &greedy_mesh(voxels), types)?,
+        Format::Stl => write_stl(&mut writer, &greedy_mesh(voxels), types)?,
+    }
+    writer.flush()
+}
+
+fn write_chunk(writer: &mut impl Write, id: &[u8; 4], content: &[u8]) -> io::Result<()> {
+    writer.write_all(id)?;
+    writer.write_u32::<LittleEndian>(content.len() as u32)?;
+    writer.write_u32::<LittleEndian>(0)?;
+    writer.write_all(content)
+}
+
+/// Writes a single model, with color index `i` being voxel type `i + 1`. The format is
+/// described in [`crate::vox`].
+pub fn write_vox(
+    writer: &mut impl Write,
+    voxels: &Array3<VoxelId>,
+    types: &[VoxelType],
+) -> io::Result<()> {
+    let shape = voxels.shape();
+    if shape.iter().any(|&x| x > 256) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            ".vox models can be at most 256 voxels wide",
+        ));
+    }
+
+    let mut size = Vec::new();
+    for &x in shape {
+        size.write_u32::<LittleEndian>(x as u32)?;
+    }
+
+    let mut xyzi = vec![0; 4];
+    let mut count = 0;
+    for ((x, y, z), &id) in voxels.indexed_iter() {
+        if is_solid(id) {
+            xyzi.extend_from_slice(&[x as u8, y as u8, z as u8, id.0 - 1]);
+            count += 1;
+        }
+    }
+    xyzi[..4].copy_from_slice(&(count as u32).to_le_bytes());
+
+    // The entry at `i` is color index `i + 1`, which is voxel type `i + 2`.
+    let mut rgba = Vec::with_capacity(256 * 4);
+    for i in 0..256 {
+        match types.get(i + 2) {
+            Some(ty) => {
+                let color: Srgb<u8> = ty.color.into_format();
+                let alpha = (ty.opacity * 255.0).round() as u8;
+                rgba.extend_from_slice(&[color.red, color.green, color.blue, alpha]);
+            }
+            None => rgba.extend_from_slice(&[0, 0, 0, 255]),
+        }
+    }
+
+    let mut children = Vec::new();
+    write_chunk(&mut children, b"SIZE", &size)?;
+    write_chunk(&mut children, b"XYZI", &xyzi)?;
+    write_chunk(&mut children, b"RGBA", &rgba)?;
+
+    writer.write_all(b"VOX ")?;
+    writer.write_u32::<LittleEndian>(150)?;
+    writer.write_all(b"MAIN")?;
+    writer.write_u32::<LittleEndian>(0)?;
+    writer.write_u32::<LittleEndian>(children.len() as u32)?;
+    writer.write_all(&children)
+}
+
+/// A rectangle covering the faces of adjacent voxels of the same type.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Quad {
+    /// Counter-clockwise when seen from the side `normal` points to.
+    pub corners: [Vector3<f32>; 4],
+    pub normal: Vector3<f32>,
+    pub id: VoxelId,
+}
+
+/// Covers the faces between solid voxels and empty space with as few quads as possible,
+/// by growing each quad along one axis of its slice and then the other.
+pub fn greedy_mesh(voxels: &Array3<VoxelId>) -> Vec<Quad> {
+    let shape = voxels.shape();
+    let size = Vector3::new(shape[0], shape[1], shape[2]).cast::<i64>();
+    let solid = |p: Vector3<i64>| {
+        (0..3).all(|i| p[i] >= 0 && p[i] < size[i])
+            && is_solid(voxels[[p.x as usize, p.y as usize, p.z as usize]])
+    };
+
+    let mut quads = Vec::new();
+    for d in 0..3 {
+        // `u`, `v` and `d` are cyclic, so `u` cross `v` points along `d`.
+        let (u, v) = ((d + 1) % 3, (d + 2) % 3);
+        for side in [-1, 1] {
+            let mut normal = Vector3::<i64>::zeros();
+            normal[d] = side;
+            for slice in 0..size[d] {
+                // The type of the face at every position of the slice, if it is exposed.
+                let mut mask = vec![None; (size[u] * size[v]) as usize];
+                for j in 0..size[v] {
+                    for i in 0..size[u] {
+                        let mut p = Vector3::zeros();
+                        p[d] = slice;
+                        p[u] = i;
+                        p[v] = j;
+                        if solid(p) && !solid(p + normal) {
+                            let p = p.map(|x| x as usize);
+                            mask[(j * size[u] + i) as usize] = Some(voxels[[p.x, p.y, p.z]]);
+                        }
+                    }
+                }
+
+                for j in 0..size[v] {
+                    let mut i = 0;
+                    while i < size[u] {
+                        let id = match mask[(j * size[u] + i) as usize] {
+                            Some(id) => id,
+                            None => {
+                                i += 1;
+                                continue;
+                            }
+                        };
+                        let at = |i: i64, j: i64| mask[(j * size[u] + i) as usize];
+                        let mut width = 1;
+                        while i + width < size[u] && at(i + width, j) == Some(id) {
+                            width += 1;
+                        }
+                        let mut height = 1;
+                        while j + height < size[v]
+                            && (i..i + width).all(|i| at(i, j + height) == Some(id))
+                        {
+                            height += 1;
+                        }
+                        for jj in j..j + height {
+                            for ii in i..i + width {
+                                mask[(jj * size[u] + ii) as usize] = None;
+                            }
+                        }
+
+                        let mut base = Vector3::zeros();
+                        base[d] = (slice + (side > 0) as i64) as f32;
+                        base[u] = i as f32;
+                        base[v] = j as f32;
+                        let mut du = Vector3::zeros();
+                        du[u] = width as f32;
+                        let mut dv = Vector3::zeros();
+                        dv[v] = height as f32;
+                        let mut corners = [base, base + du, base + du + dv, base + dv];
+                        if side < 0 {
+                            corners.reverse();
+                        }
+                        quads.push(Quad {
+                            corners,
+                            normal: normal.cast(),
+                            id,
+                        });
+
+                        i += width;
+                    }
+                }
+            }
+        }
+    }
+    quads
+}
+
+fn quad_color(quad: &Quad, types: &[VoxelType]) -> Srgb {
+    types
+        .get(quad.id.0 as usize)
+        .map_or(Srgb::new(1.0, 1.0, 1.0), |ty| ty.color)
+}
+
+pub fn write_obj(writer: &mut impl Write, quads: &[Quad], types: &[VoxelType]) -> io::Result<()> {
+    writeln!(writer, "# Exported from render-4d")?;
+    for (i, quad) in quads.iter().enumerate() {
+        let color = quad_color(quad, types);
+        for corner in &quad.corners {
+            writeln!(
+                writer,
+                "v {} {} {} {} {} {}",
+                corner.x, corner.y, corner.z, color.red, color.green, color.blue
+            )?;
+        }
+        let n = quad.normal;
+        writeln!(writer, "vn {} {} {}", n.x, n.y, n.z)?;
+        // Indices start at one.
+        let vertex = i * 4 + 1;
+        let normal = i + 1;
+        writeln!(
+            writer,
+            "f {}//{n} {}//{n} {}//{n} {}//{n}",
+            vertex,
+            vertex + 1,
+            vertex + 2,
+            vertex + 3,
+            n = normal
+        )?;
+    }
+    Ok(())
+}
+
+pub fn write_stl(writer: &mut impl Write, quads: &[Quad], types: &[VoxelType]) -> io::Result<()> {
+    let mut header = [0; 80];
+    let title = b"Exported from render-4d";
+    header[..title.len()].copy_from_slice(title);
+    writer.write_all(&header)?;
+    writer.write_u32::<LittleEndian>(quads.len() as u32 * 2)?;
+    for quad in quads {
+        let color: Srgb<u8> = quad_color(quad, types).into_format();
+        // Five bits per channel, with the top bit marking the color as valid.
+        let attribute = 0x8000
+            | (color.red as u16 >> 3) << 10
+            | (color.green as u16 >> 3) << 5
+            | color.blue as u16 >> 3;
+        let [a, b, c, d] = quad.corners;
+        for triangle in [[a, b, c], [a, c, d]] {
+            for x in quad
+                .normal
+                .iter()
+                .chain(triangle.iter().flat_map(|x| x.iter()))
+            {
+                writer.write_f32::<LittleEndian>(*x)?;
+            }
+            writer.write_u16::<LittleEndian>(attribute)?;
+        }
+    }
+    Ok(())
+}
+
+/// Exports the view to `view.vox` with F6, `view.obj` with F7 and `view.stl` with F8.
+pub fn export_system(
+    key: Res<Input<KeyCode>>,
+    device: Res<DeviceResource>,
+    queue: Res<QueueResource>,
+    view_texture: Res<ViewTexture>,
+    world: Res<World>,
+) {
+    let format = if key.just_pressed(KeyCode::F6) {
+        Format::Vox
+    } else if key.just_pressed(KeyCode::F7) {
+        Format::Obj
+    } else if key.just_pressed(KeyCode::F8) {
+        Format::Stl
+    } else {
+        return;
+    };
+    let path = PathBuf::from(format!("view.{}", format.extension()));
+    let result = view_texture
+        .read(&device, &queue)
+        .and_then(|voxels| export(&path, format, &voxels, world.types()));
+    match result {
+        Ok(()) => println!("Exported the view to {}", path.display()),
+        Err(err) => eprintln!("Failed to export the view: {}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vox::VoxFile;
+    use crate::voxel::test_types;
+    use std::io::Cursor;
+
+    /// A view of solid air with the given voxels set.
+    fn view(size: [usize; 3], voxels: &[([usize; 3], VoxelId)]) -> Array3<VoxelId> {
+        let mut view = Array3::from_elem(size, World::solid_air());
+        for &(position, id) in voxels {
+            view[position] = id;
+        }
+        view
+    }
+
+    fn area(quad: &Quad) -> f32 {
+        let [a, b, _, d] = quad.corners;
+        (b - a).cross(&(d - a)).norm()
+    }
+
+    /// Checks that the corners wind counter-clockwise around the normal.
+    fn assert_winding(quads: &[Quad]) {
+        for quad in quads {
+            let [a, b, c, d] = quad.corners;
+            for (p, q, r) in [(a, b, c), (b, c, d), (c, d, a), (d, a, b)] {
+                let turn = (q - p).cross(&(r - q));
+                assert!(turn.dot(&quad.normal) > 0.0, "{:?}", quad);
+            }
+        }
+    }
+
+    #[test]
+    fn single_voxel_has_six_faces() {
+        let quads = greedy_mesh(&view([3, 3, 3], &[([1, 1, 1], VoxelId(2))]));
+        assert_eq!(quads.len(), 6);
+        assert_winding(&quads);
+        for quad in &quads {
+            assert_eq!(area(quad), 1.0);
+            // Each face is on the side of the voxel its normal points to.
+            let center = quad.corners.iter().sum::<Vector3<f32>>() / 4.0;
+            assert_eq!(center, Vector3::repeat(1.5) + quad.normal * 0.5);
+        }
+        let normals: Vec<_> = quads.iter().map(|x| x.normal).collect();
+        for axis in 0..3 {
+            let mut normal = Vector3::zeros();
+            normal[axis] = 1.0;
+            assert!(normals.contains(&normal) && normals.contains(&-normal));
+        }
+    }
+
+    #[test]
+    fn faces_of_the_same_type_merge() {
+        let bar = view(
+            [2, 1, 1],
+            &[([0, 0, 0], VoxelId(2)), ([1, 0, 0], VoxelId(2))],
+        );
+        let quads = greedy_mesh(&bar);
+        assert_eq!(quads.len(), 6);
+        assert_winding(&quads);
+        assert_eq!(quads.iter().map(area).sum::<f32>(), 10.0);
+    }
+
+    #[test]
+    fn faces_of_different_types_dont_merge() {
+        let bar = view(
+            [2, 1, 1],
+            &[([0, 0, 0], VoxelId(2)), ([1, 0, 0], VoxelId(3))],
+        );
+        let quads = greedy_mesh(&bar);
+        // The four long sides are split in two, and the faces between the voxels are hidden.
+        assert_eq!(quads.len(), 10);
+        assert_winding(&quads);
+        assert_eq!(quads.iter().filter(|x| x.id == VoxelId(3)).count(), 5);
+    }
+
+    #[test]
+    fn mesh_files_have_every_quad() {
+        let quads = greedy_mesh(&view([1, 1, 1], &[([0, 0, 0], VoxelId(2))]));
+        let types = [VoxelType::air(), VoxelType::air(), test_types::stone()];
+
+        let mut obj = Vec::new();
+        write_obj(&mut obj, &quads, &types).unwrap();
+        let obj = String::from_utf8(obj).unwrap();
+        assert_eq!(obj.lines().filter(|x| x.starts_with("v ")).count(), 24);
+        assert_eq!(obj.lines().filter(|x| x.starts_with("f ")).count(), 6);
+
+        let mut stl = Vec::new();
+        write_stl(&mut stl, &quads, &types).unwrap();
+        let triangles = u32::from_le_bytes(stl[80..84].try_into().unwrap());
+        assert_eq!(triangles, 12);
+        // A normal, three vertices and the attribute bytes per triangle.
+        assert_eq!(stl.len(), 84 + 12 * 50);
+    }
+
+    #[test]
+    fn vox_round_trip() {
+        let mut world = World::new(1);
+        let stone = world.insert_type(test_types::stone());
+        let glass = world.insert_type(test_types::glass());
+        let voxels = view(
+            [3, 4, 5],
+            &[
+                ([0, 0, 0], stone),
+                ([2, 3, 4], glass),
+                ([1, 2, 0], World::air()),
+            ],
+        );
+        let mut bytes = Vec::new();
+        write_vox(&mut bytes, &voxels, world.types()).unwrap();
+
+        let vox = VoxFile::read_from(&mut Cursor::new(bytes)).unwrap();
+        assert_eq!(vox.models.len(), 1);
+        let model = &vox.models[0];
+        assert_eq!(model.size, Vector3::new(3, 4, 5));
+        assert_eq!(model.voxels.len(), 2);
+        assert_eq!(model.voxels[&Vector3::new(0, 0, 0)], stone.0 - 1);
+        assert_eq!(model.voxels[&Vector3::new(2, 3, 4)], glass.0 - 1);
+        let color: Srgb<u8> = test_types::glass().color.into_format();
+        let alpha = (test_types::glass().opacity * 255.0).round() as u8;
+        assert_eq!(
+            vox.palette[(glass.0 - 1) as usize],
+            [color.red, color.green, color.blue, alpha]
+        );
+    }
+}
@@ -0,0 +1,78 @@
+use crate::uniform_3d::Uniforms;
+use bevy::prelude::*;
+use bytemuck::{Pod, Zeroable};
+use palette::{LinSrgb, Srgb};
+
+/// Blends voxels in the 3D stage towards `color` by how far the 4D ray travelled before hitting
+/// them, so that depth along the view W axis stays visible.
+#[derive(Resource, Copy, Clone, Debug, PartialEq)]
+pub struct Fog {
+    pub color: Srgb,
+    /// Depth in world voxels where the fog starts.
+    pub near: f32,
+    /// Depth in world voxels where the fog reaches `strength`.
+    pub far: f32,
+    /// How much of the voxel color is replaced at `far`, between 0 and 1.
+    pub strength: f32,
+}
+
+impl Default for Fog {
+    fn default() -> Self {
+        Fog {
+            color: Srgb::new(0.0, 0.0, 0.0),
+            near: 0.0,
+            far: 128.0,
+            strength: 0.75,
+        }
+    }
+}
+
+impl Fog {
+    pub fn to_internal(self) -> FogInternal {
+        FogInternal {
+            color: self.color.into_linear(),
+            strength: self.strength,
+            near: self.near,
+            far: self.far,
+            _padding: [0.0; 2],
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct FogInternal {
+    pub color: LinSrgb,
+    pub strength: f32,
+    pub near: f32,
+    pub far: f32,
+    _padding: [f32; 2],
+}
+
+impl Default for FogInternal {
+    fn default() -> Self {
+        Fog {
+            strength: 0.0,
+            ..Default::default()
+        }
+        .to_internal()
+    }
+}
+
+unsafe impl Zeroable for FogInternal {}
+unsafe impl Pod for FogInternal {}
+
+impl FogInternal {
+    /// The 3D stage's blend between the voxel color and the fog color, from `3d.frag`.
+    pub fn apply(&self, color: LinSrgb, depth: f32) -> LinSrgb {
+        let t = ((depth - self.near) / (self.far - self.near).max(f32::EPSILON)).clamp(0.0, 1.0);
+        let amount = t * self.strength;
+        color * (1.0 - amount) + self.color * amount
+    }
+}
+
+pub fn update_uniform_system(fog: Res<Fog>, mut uniforms: ResMut<Uniforms>) {
+    if fog.is_changed() {
+        uniforms.fog = fog.to_internal();
+    }
+}
@@ -0,0 +1,375 @@
+use crate::background::{background_to_internal, Background, ViewBounds};
+use crate::depth_palette::{ColorMode, DepthPalette};
+use crate::fog::Fog;
+use crate::light::{
+    lights_to_internal, AmbientLight, DirectionalLight, LightingOptions, PointLights,
+};
+use crate::render_3d::render_offscreen;
+use crate::screenshot::read_pixels;
+use crate::surface::{
+    request_headless_device, DeviceResource, QueueResource, SurfaceConfigResource,
+};
+use crate::view::ViewSize;
+use crate::window_size::WindowSize;
+use crate::world::{World, WorldSize};
+use crate::{camera_3d, camera_4d, render_3d, render_4d, uniform_3d, uniform_4d};
+use bevy::prelude::*;
+use nalgebra::Vector2;
+use ndarray::Array3;
+use std::io;
+use std::num::NonZeroU32;
+use wgpu::*;
+
+const OFFSCREEN_FORMAT: TextureFormat = TextureFormat::Rgba8UnormSrgb;
+
+/// A texture the 3D pass renders into when there is no surface, along with a buffer the frame
+/// is copied into for reading back.
+#[derive(Resource)]
+pub struct OffscreenTarget {
+    pub texture: Texture,
+    pub buffer: Buffer,
+    pub size: Vector2<u32>,
+}
+
+impl OffscreenTarget {
+    pub fn new(device: &Device, size: Vector2<u32>) -> Self {
+        let texture = device.create_texture(&TextureDescriptor {
+            label: Some("offscreen-texture"),
+            size: Self::extent(size),
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: OFFSCREEN_FORMAT,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
+        });
+        let buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("offscreen-buffer"),
+            size: (Self::padded_bytes_per_row(size) * size.y) as u64,
+            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        OffscreenTarget {
+            texture,
+            buffer,
+            size,
+        }
+    }
+
+    pub fn extent(size: Vector2<u32>) -> Extent3d {
+        Extent3d {
+            width: size.x,
+            height: size.y,
+            depth_or_array_layers: 1,
+        }
+    }
+
+    /// Rows copied out of a texture have to be aligned to [`COPY_BYTES_PER_ROW_ALIGNMENT`].
+    pub fn padded_bytes_per_row(size: Vector2<u32>) -> u32 {
+        let align = COPY_BYTES_PER_ROW_ALIGNMENT;
+        (size.x * 4).div_ceil(align) * align
+    }
+
+    pub fn copy_to_buffer(&self, encoder: &mut CommandEncoder) {
+        encoder.copy_texture_to_buffer(
+            ImageCopyTexture {
+                texture: &self.texture,
+                mip_level: 0,
+                origin: Origin3d::ZERO,
+                aspect: TextureAspect::All,
+            },
+            ImageCopyBuffer {
+                buffer: &self.buffer,
+                layout: ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(Self::padded_bytes_per_row(self.size)),
+                    rows_per_image: NonZeroU32::new(self.size.y),
+                },
+            },
+            Self::extent(self.size),
+        );
+    }
+
+    /// Maps the readback buffer, blocking until the last copy has finished. Returns the
+    /// pixels indexed by `(y, x, channel)`, in the same layout as [`render_3d::render_cpu`].
+    pub fn read(&self, device: &Device) -> io::Result<Array3<u8>> {
+        read_pixels(device, &self.buffer, self.size, OFFSCREEN_FORMAT)
+    }
+}
+
+fn init_offscreen_target(
+    mut commands: Commands,
+    device: Res<DeviceResource>,
+    window_size: Res<WindowSize>,
+) {
+    commands.insert_resource(OffscreenTarget::new(&device, window_size.0));
+}
+
+fn update_camera_uniforms(
+    camera_3d: Res<camera_3d::Camera>,
+    camera_4d: Res<camera_4d::Camera>,
+    mut uniforms_3d: ResMut<uniform_3d::Uniforms>,
+    mut uniforms_4d: ResMut<uniform_4d::Uniforms>,
+) {
+    uniforms_3d.camera = camera_3d.to_internal();
+    uniforms_4d.camera = camera_4d.to_internal();
+}
+
+/// The resources controlling how the 3D stage colors voxels, for rendering without a window.
+#[derive(Clone, Debug)]
+pub struct Shading {
+    pub fog: Fog,
+    pub depth_palette: DepthPalette,
+    pub color_mode: ColorMode,
+    pub ambient_light: AmbientLight,
+    pub directional_light: DirectionalLight,
+    pub point_lights: PointLights,
+    pub lighting_options: LightingOptions,
+    pub background: Background,
+    pub view_bounds: ViewBounds,
+}
+
+impl Default for Shading {
+    fn default() -> Self {
+        Shading {
+            fog: Fog::default(),
+            depth_palette: DepthPalette::default(),
+            color_mode: ColorMode::Type,
+            ambient_light: AmbientLight::default(),
+            directional_light: DirectionalLight::default(),
+            point_lights: PointLights::default(),
+            lighting_options: LightingOptions::default(),
+            background: Background::default(),
+            view_bounds: ViewBounds::default(),
+        }
+    }
+}
+
+/// Renders a single frame without a window. Uses a hardware adapter if there is one, then a
+/// software adapter, and finally the CPU reference renderers if no adapter is usable.
+/// Returns sRGB pixels indexed by `(y, x, channel)`, or an error if reading the frame back from
+/// the adapter failed.
+pub fn render_still(
+    world: World,
+    camera_3d: camera_3d::Camera,
+    camera_4d: camera_4d::Camera,
+    view_size: ViewSize,
+    window_size: WindowSize,
+    shading: Shading,
+) -> io::Result<Array3<u8>> {
+    let world_size = WorldSize(world.size());
+    let (device, queue) = match request_headless_device() {
+        Some(device) => device,
+        None => {
+            eprintln!("No usable adapter, falling back to the CPU renderer");
+            return Ok(render_still_cpu(
+                &world,
+                &camera_3d,
+                &camera_4d,
+                view_size,
+                window_size,
+                &shading,
+            ));
+        }
+    };
+
+    let mut app = App::new();
+    app.insert_resource(world_size)
+        .insert_resource(view_size)
+        .insert_resource(window_size)
+        .insert_resource(shading.fog)
+        .insert_resource(shading.depth_palette)
+        .insert_resource(shading.color_mode)
+        .insert_resource(shading.ambient_light)
+        .insert_resource(shading.directional_light)
+        .insert_resource(shading.point_lights)
+        .insert_resource(shading.lighting_options)
+        .insert_resource(shading.background)
+        .insert_resource(shading.view_bounds)
+        .insert_resource(world)
+        .insert_resource(camera_3d)
+        .insert_resource(camera_4d)
+        .insert_resource(SurfaceConfigResource(SurfaceConfiguration {
+            usage: TextureUsages::RENDER_ATTACHMENT,
+            format: OFFSCREEN_FORMAT,
+            width: window_size.0.x,
+            height: window_size.0.y,
+            present_mode: PresentMode::Fifo,
+        }))
+        .insert_resource(DeviceResource(device))
+        .insert_resource(QueueResource(queue));
+    crate::add_render_systems(&mut app);
+    app.add_startup_system_to_stage("startup-bind-groups", init_offscreen_target)
+        .add_system(
+            update_camera_uniforms
+                .before("update-uniforms-4d")
+                .before("update-uniforms-3d"),
+        )
+        .add_system(
+            render_offscreen
+                .label("render-3d")
+                .after("update-uniforms-3d")
+                .after("render-4d"),
+        );
+    app.update();
+
+    let device = app.world.resource::<DeviceResource>();
+    app.world.resource::<OffscreenTarget>().read(device)
+}
+
+pub fn render_still_cpu(
+    world: &World,
+    camera_3d: &camera_3d::Camera,
+    camera_4d: &camera_4d::Camera,
+    view_size: ViewSize,
+    window_size: WindowSize,
+    shading: &Shading,
+) -> Array3<u8> {
+    let camera_4d = camera_4d.to_internal();
+    let view = render_4d::trace_view_cpu(world, &camera_4d, view_size);
+    let uniforms = uniform_3d::Uniforms::new(
+        camera_3d.to_internal(),
+        window_size.0.cast(),
+        shading.fog.to_internal(),
+        shading.depth_palette.to_internal(shading.color_mode),
+        lights_to_internal(
+            &shading.ambient_light,
+            &shading.directional_light,
+            &shading.point_lights,
+            &shading.lighting_options,
+        ),
+        background_to_internal(&shading.background, &shading.view_bounds),
+        world.types_internal(),
+    );
+    render_3d::render_cpu(&view, &uniforms, &shading.background)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{read_ppm, write_ppm};
+    use nalgebra::Vector3;
+    use std::f32::consts::FRAC_PI_4;
+
+    /// Rendered from [`GoldenScene`] by `update_golden_image`.
+    const GOLDEN_IMAGE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/golden/default_scene.ppm"
+    );
+
+    /// The default scene from the initial 4D camera, with a view and window small enough for
+    /// the CPU renderer to be quick. The 3D camera looks down at the view volume from outside
+    /// of one of its corners.
+    struct GoldenScene {
+        world: World,
+        camera_3d: camera_3d::Camera,
+        camera_4d: camera_4d::Camera,
+        view_size: ViewSize,
+        window_size: WindowSize,
+    }
+
+    impl GoldenScene {
+        fn new() -> Self {
+            let mut world = World::new(88);
+            crate::build_world_data(&mut world);
+            let camera_4d = camera_4d::Camera::new(WorldSize(world.size()));
+            let mut camera_3d = camera_3d::Camera::new(Vector3::new(-14.0, -14.0, 40.0), FRAC_PI_4);
+            camera_3d.y += 0.5;
+            GoldenScene {
+                world,
+                camera_3d,
+                camera_4d,
+                view_size: ViewSize(32),
+                window_size: WindowSize(Vector2::new(96, 96)),
+            }
+        }
+
+        /// Renders on an adapter, rather than falling back to the CPU like [`render_still`].
+        fn render_gpu(self) -> Array3<u8> {
+            assert!(
+                request_headless_device().is_some(),
+                "No usable adapter to render the golden scene with"
+            );
+            render_still(
+                self.world,
+                self.camera_3d,
+                self.camera_4d,
+                self.view_size,
+                self.window_size,
+                Shading::default(),
+            )
+            .unwrap()
+        }
+
+        fn render_cpu(self) -> Array3<u8> {
+            render_still_cpu(
+                &self.world,
+                &self.camera_3d,
+                &self.camera_4d,
+                self.view_size,
+                self.window_size,
+                &Shading::default(),
+            )
+        }
+    }
+
+    /// How far a color channel may be off anywhere, for rounding differences.
+    const TOLERANCE: i32 = 2;
+    /// How far a few channels may be off, where the GPU rounds ray steps differently at voxel
+    /// edges and so shades a pixel with the face next to it.
+    const OUTLIER_TOLERANCE: i32 = 24;
+    /// How many channels in 1000 may be outliers.
+    const OUTLIERS_PER_MILLE: usize = 5;
+
+    fn assert_matches_golden(pixels: &Array3<u8>) {
+        let golden = read_ppm(GOLDEN_IMAGE).expect("Failed to load golden image");
+        assert_eq!(golden.dim(), pixels.dim());
+
+        let mut outliers = 0;
+        for ((y, x, channel), &value) in pixels.indexed_iter() {
+            if channel == 3 {
+                continue;
+            }
+            let expected = golden[[y, x, channel]];
+            let difference = (value as i32 - expected as i32).abs();
+            assert!(
+                difference <= OUTLIER_TOLERANCE,
+                "Pixel ({}, {}) is {} instead of {}",
+                x,
+                y,
+                value,
+                expected
+            );
+            if difference > TOLERANCE {
+                outliers += 1;
+            }
+        }
+        let (height, width, _) = pixels.dim();
+        let max_outliers = width * height * 3 * OUTLIERS_PER_MILLE / 1000;
+        assert!(
+            outliers <= max_outliers,
+            "{} channels differ from the golden image, at most {} may",
+            outliers,
+            max_outliers
+        );
+    }
+
+    #[test]
+    fn cpu_render_matches_golden_image() {
+        assert_matches_golden(&GoldenScene::new().render_cpu());
+    }
+
+    #[test]
+    #[ignore = "needs a GPU adapter, run with `cargo test gpu_render -- --ignored`"]
+    fn gpu_render_matches_golden_image() {
+        assert_matches_golden(&GoldenScene::new().render_gpu());
+    }
+
+    /// Rewrites the golden image from the GPU renderer after an intended change to the
+    /// rendering, with `cargo test update_golden_image -- --ignored`.
+    #[test]
+    #[ignore = "rewrites the golden image"]
+    fn update_golden_image() {
+        let pixels = GoldenScene::new().render_gpu();
+        write_ppm(GOLDEN_IMAGE, &pixels).expect("Failed to write golden image");
+    }
+}
@@ -0,0 +1,428 @@
+use crate::csg::{self, Operand, Operation, Priority};
+use crate::region::Region;
+use crate::voxel::{VoxelId, VoxelType};
+use crate::world::{TooManyTypes, World};
+use bevy::prelude::*;
+use std::collections::VecDeque;
+use std::iter::repeat_n;
+use std::mem::size_of;
+
+/// How much memory the undo stack may use before the oldest edits are forgotten.
+pub const MAX_HISTORY_BYTES: usize = 16 * 1024 * 1024;
+
+/// `length` consecutive voxels that were `before` and became `after`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Run {
+    length: u32,
+    before: VoxelId,
+    after: VoxelId,
+}
+
+/// The voxels of a region before and after an edit, run length encoded in the order of
+/// [`Region::positions`].
+#[derive(Clone, Debug)]
+struct VoxelDiff {
+    region: Region,
+    runs: Vec<Run>,
+}
+
+impl VoxelDiff {
+    /// The diff for setting the voxels of the region to `voxels`, given in the order of
+    /// [`Region::positions`].
+    fn new(world: &World, region: Region, voxels: impl IntoIterator<Item = VoxelId>) -> Self {
+        let mut runs: Vec<Run> = Vec::new();
+        for (position, after) in region.positions().zip(voxels) {
+            let before = world[position];
+            match runs.last_mut() {
+                Some(run) if run.before == before && run.after == after => run.length += 1,
+                _ => runs.push(Run {
+                    length: 1,
+                    before,
+                    after,
+                }),
+            }
+        }
+        VoxelDiff { region, runs }
+    }
+
+    fn apply(&self, world: &mut World, forward: bool) {
+        let voxels = self.runs.iter().flat_map(|run| {
+            let id = if forward { run.after } else { run.before };
+            repeat_n(id, run.length as usize)
+        });
+        world.write_region(self.region, voxels);
+    }
+}
+
+/// One undoable step: the types it inserted, then the voxels it changed.
+#[derive(Clone, Debug, Default)]
+struct Edit {
+    /// The inserted types along with the ids they got, in the order they were inserted.
+    types: Vec<(VoxelId, VoxelType)>,
+    voxels: Option<VoxelDiff>,
+}
+
+impl Edit {
+    fn size(&self) -> usize {
+        let runs = self.voxels.as_ref().map_or(0, |diff| diff.runs.len());
+        size_of::<Edit>()
+            + self.types.len() * size_of::<(VoxelId, VoxelType)>()
+            + runs * size_of::<Run>()
+    }
+
+    /// Removes exactly the types the edit inserted, even if others were inserted since.
+    fn undo(&self, world: &mut World) {
+        if let Some(diff) = &self.voxels {
+            diff.apply(world, false);
+        }
+        for &(id, _) in self.types.iter().rev() {
+            world.remove_type(id);
+        }
+    }
+
+    fn redo(&self, world: &mut World) {
+        for &(id, ty) in &self.types {
+            world.insert_type_at(id, ty);
+        }
+        if let Some(diff) = &self.voxels {
+            diff.apply(world, true);
+        }
+    }
+}
+
+/// Edits made to the world through it, which can be undone and redone. Making a new edit
+/// clears the edits that were undone.
+#[derive(Resource, Clone, Debug)]
+pub struct History {
+    undo: VecDeque<Edit>,
+    redo: Vec<Edit>,
+    /// Memory used by `undo`.
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new(MAX_HISTORY_BYTES)
+    }
+}
+
+impl History {
+    pub fn new(max_bytes: usize) -> Self {
+        History {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            bytes: 0,
+            max_bytes,
+        }
+    }
+
+    /// Combines the world with `operand` like [`csg`], clipped to the bounds of the world.
+    /// The types a world operand needs are inserted as part of the same edit. Returns whether
+    /// anything changed, and leaves the world as it was if the types don't fit.
+    pub fn apply(
+        &mut self,
+        world: &mut World,
+        region: Region,
+        operand: Operand,
+        operation: Operation,
+        priority: Priority,
+    ) -> Result<bool, TooManyTypes> {
+        let region = region.intersection(&world.bounds());
+        if region.is_empty() {
+            return Ok(false);
+        }
+        let types = csg::insert_types(world, region, operand)?;
+        let voxels = csg::combine_region(world, region, operand, operation, priority);
+        let diff = VoxelDiff::new(world, region, voxels.iter().copied());
+        if types.is_empty() && diff.runs.iter().all(|run| run.before == run.after) {
+            return Ok(false);
+        }
+        world.write_region(region, voxels);
+        self.push(Edit {
+            types,
+            voxels: Some(diff),
+        });
+        Ok(true)
+    }
+
+    fn push(&mut self, edit: Edit) {
+        self.redo.clear();
+        self.push_undo(edit);
+    }
+
+    /// Adds an edit to the undo stack, forgetting the oldest edits while it is over the limit.
+    fn push_undo(&mut self, edit: Edit) {
+        self.bytes += edit.size();
+        self.undo.push_back(edit);
+        // The newest edit is kept even if it is larger than the limit on its own.
+        while self.bytes > self.max_bytes && self.undo.len() > 1 {
+            let edit = self.undo.pop_front().unwrap();
+            self.bytes -= edit.size();
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Reverts the last edit, returning whether there was one.
+    pub fn undo(&mut self, world: &mut World) -> bool {
+        match self.undo.pop_back() {
+            Some(edit) => {
+                edit.undo(world);
+                self.bytes -= edit.size();
+                self.redo.push(edit);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Makes the last undone edit again, returning whether there was one.
+    pub fn redo(&mut self, world: &mut World) -> bool {
+        match self.redo.pop() {
+            Some(edit) => {
+                edit.redo(world);
+                self.push_undo(edit);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Ctrl+Z undoes and Ctrl+Y or Ctrl+Shift+Z redoes.
+pub fn history_system(
+    key: Res<Input<KeyCode>>,
+    mut history: ResMut<History>,
+    mut world: ResMut<World>,
+) {
+    if !key.any_pressed([KeyCode::LControl, KeyCode::RControl]) {
+        return;
+    }
+    let shift = key.any_pressed([KeyCode::LShift, KeyCode::RShift]);
+    let undo = key.just_pressed(KeyCode::Z) && !shift;
+    let redo = key.just_pressed(KeyCode::Y) || (key.just_pressed(KeyCode::Z) && shift);
+    // Only borrows the world mutably when something changes, so it isn't uploaded otherwise.
+    if undo && history.can_undo() {
+        history.undo(&mut world);
+    } else if redo && history.can_redo() {
+        history.redo(&mut world);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shapes::{draw, Hypersphere, Shape};
+    use crate::voxel::test_types;
+    use nalgebra::Vector4;
+
+    fn region(min: [u32; 4], max: [u32; 4]) -> Region {
+        Region::new(Vector4::from(min), Vector4::from(max))
+    }
+
+    /// Sets the voxels in the region to `id` through the history.
+    fn fill(history: &mut History, world: &mut World, region: Region, id: VoxelId) {
+        let (operation, id) = if id == World::air() {
+            (Operation::Difference, World::solid_air())
+        } else {
+            (Operation::Union, id)
+        };
+        let operand = Operand::Shape(&region, id);
+        history
+            .apply(world, region, operand, operation, Priority::Operand)
+            .unwrap();
+    }
+
+    fn set_voxel(history: &mut History, world: &mut World, position: Vector4<u32>, id: VoxelId) {
+        fill(history, world, Region::point(position), id);
+    }
+
+    /// The world as it is saved, which covers every voxel and type.
+    fn to_bytes(world: &World) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        world.write_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// A world with `ty` at the origin, to be combined with another world.
+    fn operand_world(ty: VoxelType) -> World {
+        let mut world = World::new(8);
+        let id = world.insert_type(ty);
+        world.set(Vector4::zeros(), id);
+        world
+    }
+
+    fn union(
+        history: &mut History,
+        world: &mut World,
+        other: &World,
+    ) -> Result<bool, TooManyTypes> {
+        let region = world.bounds();
+        history.apply(
+            world,
+            region,
+            Operand::World(other),
+            Operation::Union,
+            Priority::Operand,
+        )
+    }
+
+    #[test]
+    fn undo_and_redo_restore_the_world_exactly() {
+        let mut world = World::new(16);
+        let stone = world.insert_type(test_types::stone());
+        draw(&mut world, &region([0, 0, 0, 0], [16, 16, 4, 16]), stone);
+        let mut history = History::default();
+
+        let mut snapshots = vec![to_bytes(&world)];
+        set_voxel(&mut history, &mut world, Vector4::new(3, 4, 5, 6), stone);
+        snapshots.push(to_bytes(&world));
+        fill(
+            &mut history,
+            &mut world,
+            region([2, 2, 2, 2], [10, 9, 8, 7]),
+            World::air(),
+        );
+        snapshots.push(to_bytes(&world));
+        // Inserts glass, but reuses stone.
+        let mut other = operand_world(test_types::glass());
+        let other_stone = other.insert_type(test_types::stone());
+        draw(&mut other, &region([6, 0, 0, 0], [8, 8, 6, 3]), other_stone);
+        assert!(union(&mut history, &mut world, &other).unwrap());
+        assert_eq!(world.types().len(), 4);
+        snapshots.push(to_bytes(&world));
+        let ball = Hypersphere {
+            center: Vector4::repeat(5.0),
+            radius: 3.0,
+        };
+        let operand = Operand::Shape(&ball, stone);
+        let changed = history
+            .apply(
+                &mut world,
+                ball.region(16),
+                operand,
+                Operation::Xor,
+                Priority::Target,
+            )
+            .unwrap();
+        assert!(changed);
+        snapshots.push(to_bytes(&world));
+        set_voxel(
+            &mut history,
+            &mut world,
+            Vector4::new(1, 1, 1, 1),
+            World::air(),
+        );
+        snapshots.push(to_bytes(&world));
+
+        for snapshot in snapshots.iter().rev().skip(1) {
+            assert!(history.undo(&mut world));
+            assert!(to_bytes(&world) == *snapshot);
+        }
+        assert!(!history.undo(&mut world));
+
+        for snapshot in &snapshots[1..] {
+            assert!(history.redo(&mut world));
+            assert!(to_bytes(&world) == *snapshot);
+        }
+        assert!(!history.redo(&mut world));
+    }
+
+    #[test]
+    fn undoing_a_type_insertion_removes_that_type() {
+        let mut world = World::new(8);
+        let mut history = History::default();
+        // Only covers the origin, so that undoing it leaves the rest of the world alone.
+        let other = operand_world(test_types::stone());
+        history
+            .apply(
+                &mut world,
+                Region::point(Vector4::zeros()),
+                Operand::World(&other),
+                Operation::Union,
+                Priority::Operand,
+            )
+            .unwrap();
+        let stone = VoxelId(2);
+        assert_eq!(world[Vector4::zeros()], stone);
+        // Inserted after the recorded one, without going through the history.
+        let glass = world.insert_type(test_types::glass());
+        let position = Vector4::new(1, 2, 3, 4);
+        world.set(position, glass);
+
+        history.undo(&mut world);
+        assert_eq!(
+            world.types(),
+            &[VoxelType::air(), VoxelType::air(), test_types::glass()]
+        );
+        // The other type and its voxels move down into the freed id.
+        assert_eq!(world[position], VoxelId(2));
+        assert_eq!(world[Vector4::zeros()], World::air());
+
+        history.redo(&mut world);
+        assert_eq!(
+            world.types()[2..],
+            [test_types::stone(), test_types::glass()]
+        );
+        assert_eq!(world[position], glass);
+        assert_eq!(world[Vector4::zeros()], stone);
+    }
+
+    #[test]
+    fn edits_that_dont_fit_change_nothing() {
+        let mut world = World::new(8);
+        for i in 2..=255 {
+            world.insert_type(test_types::filler(i));
+        }
+        let mut history = History::default();
+        let before = to_bytes(&world);
+        assert_eq!(
+            union(
+                &mut history,
+                &mut world,
+                &operand_world(test_types::stone())
+            ),
+            Err(TooManyTypes)
+        );
+        assert!(to_bytes(&world) == before);
+        assert!(!history.can_undo());
+        // Nothing is recorded for edits that don't change anything.
+        let air = World::new(8);
+        assert_eq!(union(&mut history, &mut world, &air), Ok(false));
+        assert!(!history.can_undo());
+    }
+
+    #[test]
+    fn redo_stays_within_the_memory_limit() {
+        let mut world = World::new(8);
+        let mut history = History::default();
+        for x in 0..8 {
+            set_voxel(
+                &mut history,
+                &mut world,
+                Vector4::new(x, 0, 0, 0),
+                World::solid_air(),
+            );
+        }
+        let edit_size = history.undo[0].size();
+        history.max_bytes = edit_size * 3;
+        while history.undo(&mut world) {}
+        assert_eq!(history.bytes, 0);
+
+        while history.redo(&mut world) {
+            assert!(history.bytes <= history.max_bytes);
+        }
+        assert_eq!(history.undo.len(), 3);
+        // The edits that were forgotten can't be undone anymore, but the rest can.
+        while history.undo(&mut world) {}
+        assert_eq!(world[Vector4::new(4, 0, 0, 0)], World::solid_air());
+        assert_eq!(world[Vector4::new(5, 0, 0, 0)], World::air());
+    }
+}
@@ -0,0 +1,234 @@
+use crate::uniform_3d::Uniforms;
+use bevy::prelude::*;
+use bytemuck::{Pod, Zeroable};
+use nalgebra::Vector3;
+use palette::{LinSrgb, Srgb};
+
+/// Point lights past this many are ignored by the 3D stage.
+pub const MAX_POINT_LIGHTS: usize = 8;
+/// How far shadow rays start in front of the face, so that they don't hit its own voxel.
+pub const SHADOW_BIAS: f32 = 0.01;
+
+/// Light reaching every face equally, regardless of its normal.
+#[derive(Resource, Copy, Clone, Debug, PartialEq)]
+pub struct AmbientLight {
+    pub color: Srgb,
+    pub intensity: f32,
+}
+
+impl Default for AmbientLight {
+    fn default() -> Self {
+        AmbientLight {
+            color: Srgb::new(1.0, 1.0, 1.0),
+            intensity: 0.25,
+        }
+    }
+}
+
+/// Light coming from infinitely far away, like the sun.
+#[derive(Resource, Copy, Clone, Debug, PartialEq)]
+pub struct DirectionalLight {
+    /// The direction the light travels in, in view coordinates.
+    pub direction: Vector3<f32>,
+    pub color: Srgb,
+    pub intensity: f32,
+}
+
+impl Default for DirectionalLight {
+    fn default() -> Self {
+        DirectionalLight {
+            direction: Vector3::new(-0.3, -1.0, -0.6),
+            color: Srgb::new(1.0, 1.0, 1.0),
+            intensity: 0.8,
+        }
+    }
+}
+
+/// Which of the more expensive lighting effects the 3D stage computes.
+#[derive(Resource, Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LightingOptions {
+    /// Casts a ray towards every light to check whether voxels are in the way.
+    pub shadows: bool,
+    /// Darkens ambient light in corners, depending on the neighbors of each face.
+    pub ambient_occlusion: bool,
+}
+
+impl Default for LightingOptions {
+    fn default() -> Self {
+        LightingOptions {
+            shadows: true,
+            ambient_occlusion: true,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PointLight {
+    /// The position of the light, in view coordinates.
+    pub position: Vector3<f32>,
+    pub color: Srgb,
+    pub intensity: f32,
+    /// The distance at which the light has faded out completely.
+    pub range: f32,
+}
+
+/// The point lights in the view, of which the first [`MAX_POINT_LIGHTS`] are used.
+#[derive(Resource, Clone, Debug, Default, PartialEq)]
+pub struct PointLights(pub Vec<PointLight>);
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct PointLightInternal {
+    pub position: Vector3<f32>,
+    pub range: f32,
+    pub color: LinSrgb,
+    _padding: f32,
+}
+
+impl Default for PointLightInternal {
+    fn default() -> Self {
+        PointLightInternal {
+            position: Vector3::zeros(),
+            range: 0.0,
+            color: LinSrgb::new(0.0, 0.0, 0.0),
+            _padding: 0.0,
+        }
+    }
+}
+
+unsafe impl Zeroable for PointLightInternal {}
+unsafe impl Pod for PointLightInternal {}
+
+/// All lights, with their colors multiplied by their intensities.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct LightsInternal {
+    pub ambient: LinSrgb,
+    pub point_light_count: u32,
+    /// The direction towards the directional light.
+    pub to_directional: Vector3<f32>,
+    pub shadows: u32,
+    pub directional: LinSrgb,
+    pub ambient_occlusion: u32,
+    pub point_lights: [PointLightInternal; MAX_POINT_LIGHTS],
+}
+
+impl Default for LightsInternal {
+    fn default() -> Self {
+        lights_to_internal(
+            &AmbientLight::default(),
+            &DirectionalLight::default(),
+            &PointLights::default(),
+            &LightingOptions::default(),
+        )
+    }
+}
+
+unsafe impl Zeroable for LightsInternal {}
+unsafe impl Pod for LightsInternal {}
+
+pub fn lights_to_internal(
+    ambient: &AmbientLight,
+    directional: &DirectionalLight,
+    point_lights: &PointLights,
+    options: &LightingOptions,
+) -> LightsInternal {
+    let mut internal_point_lights = [PointLightInternal::default(); MAX_POINT_LIGHTS];
+    for (internal, light) in internal_point_lights.iter_mut().zip(&point_lights.0) {
+        *internal = PointLightInternal {
+            position: light.position,
+            range: light.range,
+            color: light.color.into_linear() * light.intensity,
+            _padding: 0.0,
+        };
+    }
+    LightsInternal {
+        ambient: ambient.color.into_linear() * ambient.intensity,
+        point_light_count: point_lights.0.len().min(MAX_POINT_LIGHTS) as u32,
+        to_directional: -directional.direction.normalize(),
+        shadows: options.shadows as u32,
+        directional: directional.color.into_linear() * directional.intensity,
+        ambient_occlusion: options.ambient_occlusion as u32,
+        point_lights: internal_point_lights,
+    }
+}
+
+/// Light arriving at a face, split into the part that is scattered by the surface and tinted
+/// by its color, and the part that is reflected towards the camera.
+pub struct Lighting {
+    pub diffuse: LinSrgb,
+    pub specular: LinSrgb,
+}
+
+impl LightsInternal {
+    /// Lambert diffuse and Blinn-Phong specular lighting for a face, as in `3d.frag`.
+    /// `to_camera` is the normalized direction from the face to the camera, and
+    /// `ambient_occlusion` scales the ambient light. `shadow(origin, direction, max_distance)`
+    /// returns how much of the light travelling along the ray arrives.
+    pub fn light_face(
+        &self,
+        position: Vector3<f32>,
+        normal: Vector3<f32>,
+        to_camera: Vector3<f32>,
+        roughness: f32,
+        ambient_occlusion: f32,
+        shadow: impl Fn(Vector3<f32>, Vector3<f32>, f32) -> f32,
+    ) -> Lighting {
+        let mut lighting = Lighting {
+            diffuse: self.ambient * ambient_occlusion,
+            specular: LinSrgb::new(0.0, 0.0, 0.0),
+        };
+        let mut add_light = |to_light: Vector3<f32>, max_distance: f32, mut color: LinSrgb| {
+            let n_dot_l = normal.dot(&to_light).max(0.0);
+            if self.shadows != 0 && n_dot_l > 0.0 {
+                color *= shadow(position + normal * SHADOW_BIAS, to_light, max_distance);
+            }
+            lighting.diffuse += color * n_dot_l;
+            let halfway = (to_light + to_camera).normalize();
+            let shininess = 2.0 / (roughness * roughness).max(f32::EPSILON) - 2.0;
+            let highlight = if roughness < 1.0 {
+                normal.dot(&halfway).max(0.0).powf(shininess)
+            } else {
+                0.0
+            };
+            lighting.specular += color * (n_dot_l * (1.0 - roughness) * highlight);
+        };
+        add_light(self.to_directional, f32::INFINITY, self.directional);
+        for light in &self.point_lights[..self.point_light_count as usize] {
+            let to_light = light.position - position;
+            let distance = to_light.norm();
+            let attenuation = (1.0 - distance / light.range.max(f32::EPSILON)).clamp(0.0, 1.0);
+            add_light(
+                to_light / distance.max(f32::EPSILON),
+                distance,
+                light.color * (attenuation * attenuation),
+            );
+        }
+        lighting
+    }
+}
+
+pub fn lighting_options_system(key: Res<Input<KeyCode>>, mut options: ResMut<LightingOptions>) {
+    if key.just_pressed(KeyCode::L) {
+        options.shadows = !options.shadows;
+    }
+    if key.just_pressed(KeyCode::O) {
+        options.ambient_occlusion = !options.ambient_occlusion;
+    }
+}
+
+pub fn update_uniform_system(
+    ambient: Res<AmbientLight>,
+    directional: Res<DirectionalLight>,
+    point_lights: Res<PointLights>,
+    options: Res<LightingOptions>,
+    mut uniforms: ResMut<Uniforms>,
+) {
+    if ambient.is_changed()
+        || directional.is_changed()
+        || point_lights.is_changed()
+        || options.is_changed()
+    {
+        uniforms.lights = lights_to_internal(&ambient, &directional, &point_lights, &options);
+    }
+}
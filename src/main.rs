@@ -1,30 +1,95 @@
 #![feature(div_duration)]
 
+use crate::background::{init_background, Background, BackgroundImage, ViewBounds};
+use crate::csg::{Operand, Operation, Priority};
+use crate::depth_palette::{ColorMode, DepthPalette};
+use crate::edit::{Brush, SelectedType};
+use crate::fog::Fog;
+use crate::history::History;
+use crate::light::{AmbientLight, DirectionalLight, LightingOptions, PointLights};
+use crate::picking::{PickedVoxel, ViewVoxels};
+use crate::region::Region;
+use crate::screenshot::Screenshot;
 use crate::surface::init_surface;
+use crate::terrain::Generator;
 use crate::view::{init_view, ViewSize};
+use crate::vox::{Import, Placement, VoxFile};
 use crate::voxel::VoxelType;
-use crate::window_size::{init_window_size, update_window_size};
+use crate::window_size::{init_window_size, update_window_size, WindowSize};
 use crate::world::{init_world, update_world, World, WorldSize};
+use crate::world_file::{save_world_system, WorldPath};
 use bevy::diagnostic::{DiagnosticsPlugin, FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin};
 use bevy::prelude::*;
-use nalgebra::{Vector3, Vector4};
+use nalgebra::{Vector2, Vector3, Vector4};
 use palette::Srgb;
+use std::path::{Path, PathBuf};
 use surface::update_surface;
 
+mod background;
 mod camera_3d;
 mod camera_4d;
+mod csg;
+mod depth_palette;
+mod edit;
+mod export;
+mod fog;
+mod headless;
+mod history;
+mod light;
+mod noise;
+mod picking;
+mod region;
 mod render_3d;
 mod render_4d;
+mod screenshot;
+mod shapes;
 mod surface;
+mod terrain;
 mod uniform_3d;
 mod uniform_4d;
 mod utils;
 mod view;
+mod vox;
 mod voxel;
 mod window_size;
 mod world;
+mod world_file;
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let world_path = PathBuf::from(flag_value(&args, "--world").unwrap_or("world.r4d"));
+    let terrain_seed = flag_value(&args, "--terrain").map(|seed| {
+        seed.parse::<u32>()
+            .expect("Terrain seed must be an integer")
+    });
+    let generator = flag_value(&args, "--generator").map_or(Generator::Hills, |name| {
+        Generator::from_name(name).expect("Generator must be hills, caves or crystals")
+    });
+    let mut world = load_world(&world_path, terrain_seed, generator);
+    let mut history = History::default();
+    combine_worlds(&args, &mut world, &mut history);
+    import_model(&args, &mut world);
+    if let Some(path) = flag_value(&args, "--headless") {
+        let size = flag_value(&args, "--size").map_or(Vector2::new(500, 500), |size| {
+            parse_size(size).expect("Size must be given as <width>x<height>")
+        });
+        render_headless(path, size, world);
+        return;
+    }
+    if let Some(path) = flag_value(&args, "--export") {
+        export_view(path, world);
+        return;
+    }
+    let background = match flag_value(&args, "--background") {
+        Some(color) if color.starts_with('#') => Background::Solid(
+            background::parse_hex_color(color).expect("Background color must be #rrggbb"),
+        ),
+        Some(path) => {
+            Background::Image(BackgroundImage::load(path).expect("Failed to load background"))
+        }
+        None => Background::default(),
+    };
+
     let mut app = App::new();
     app.insert_resource(WindowDescriptor {
         title: "render-4d".to_string(),
@@ -32,16 +97,98 @@ fn main() {
         height: 500.0,
         ..Default::default()
     })
-    .insert_resource(WorldSize(88))
     .insert_resource(ViewSize(128))
-    .insert_resource(camera_3d::Camera::new(Vector3::new(4.0, 4.0, 4.0), 0.0))
-    .insert_resource(camera_4d::Camera::new());
+    .insert_resource(Fog::default())
+    .insert_resource(DepthPalette::default())
+    .insert_resource(ColorMode::Type)
+    .insert_resource(AmbientLight::default())
+    .insert_resource(DirectionalLight::default())
+    .insert_resource(PointLights::default())
+    .insert_resource(LightingOptions::default())
+    .insert_resource(background)
+    .insert_resource(ViewBounds::default())
+    .insert_resource(ViewVoxels::default())
+    .insert_resource(PickedVoxel::default())
+    .insert_resource(SelectedType::default())
+    .insert_resource(Brush::default())
+    .insert_resource(history)
+    .insert_resource(Screenshot::default())
+    .insert_resource(camera_3d::Camera::new(Vector3::new(4.0, 4.0, 4.0), 0.0));
+    let world_size = WorldSize(world.size());
+    app.insert_resource(world);
+    app.insert_resource(world_size)
+        .insert_resource(camera_4d::Camera::new(world_size));
+    app.insert_resource(WorldPath(world_path));
     app.add_plugins(DefaultPlugins)
         .add_plugin(DiagnosticsPlugin)
         .add_plugin(LogDiagnosticsPlugin::default())
         .add_plugin(FrameTimeDiagnosticsPlugin)
         .add_plugin(camera_3d::CameraPlugin)
         .add_plugin(camera_4d::CameraPlugin);
+    add_render_systems(&mut app);
+    app.add_startup_system(init_window_size)
+        .add_startup_system_to_stage("startup-surface", init_surface)
+        .add_system(update_window_size.before("update-surface"))
+        .add_system(update_surface.label("update-surface"))
+        .add_system(save_world_system)
+        .add_system(export::export_system.after("render-4d"))
+        .add_system(depth_palette::color_mode_system.before(depth_palette::update_uniform_system))
+        .add_system(light::lighting_options_system.before(light::update_uniform_system))
+        .add_system(background::view_bounds_system.before(background::update_background))
+        .add_system(picking::read_view_system.before("picking"))
+        .add_system(
+            picking::picking_system
+                .label("picking")
+                .before("camera-3d")
+                .before("camera-4d"),
+        )
+        .add_system(edit::select_type_system)
+        .add_system(edit::brush_system.before(edit::edit_system))
+        .add_system(history::history_system.before("update-world"))
+        .add_system(
+            edit::edit_system
+                .after("picking")
+                .before("camera-3d")
+                .before("update-world"),
+        )
+        .add_system(screenshot::request_screenshot_system.before("render-3d"))
+        .add_system(
+            render_3d::render
+                .label("render-3d")
+                .after("update-uniforms-3d")
+                .after("render-4d"),
+        )
+        .add_system(screenshot::save_screenshot_system.after("render-3d"));
+    app.run();
+}
+
+/// The argument following `flag`, if it was given.
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    let index = args.iter().position(|arg| arg == flag)?;
+    args.get(index + 1).map(String::as_str)
+}
+
+/// Parses a size like `1920x1080`.
+fn parse_size(size: &str) -> Option<Vector2<u32>> {
+    let (width, height) = size.split_once('x')?;
+    Some(Vector2::new(width.parse().ok()?, height.parse().ok()?))
+}
+
+/// Parses a position like `1,2,3,4`.
+fn parse_position(position: &str) -> Option<Vector4<i64>> {
+    let coordinates = position
+        .split(',')
+        .map(|x| x.parse().ok())
+        .collect::<Option<Vec<_>>>()?;
+    match coordinates[..] {
+        [x, y, z, w] => Some(Vector4::new(x, y, z, w)),
+        _ => None,
+    }
+}
+
+/// Adds the startup stages and the systems shared by windowed and headless rendering.
+/// The caller provides the device, either through `init_surface` or directly.
+pub fn add_render_systems(app: &mut App) {
     app.add_startup_stage_after(
         StartupStage::Startup,
         "startup-surface",
@@ -62,64 +209,146 @@ fn main() {
         "startup-finish",
         SystemStage::single_threaded(),
     );
-    app.add_startup_system(init_window_size)
-        .add_startup_system_to_stage("startup-surface", init_surface)
-        .add_startup_system_to_stage("startup-bind-groups", uniform_4d::init_uniforms)
+    app.add_startup_system_to_stage("startup-bind-groups", uniform_4d::init_uniforms)
         .add_startup_system_to_stage("startup-bind-groups", uniform_3d::init_uniforms)
         .add_startup_system_to_stage("startup-bind-groups", init_world)
         .add_startup_system_to_stage("startup-bind-groups", init_view)
+        .add_startup_system_to_stage("startup-bind-groups", init_background)
         .add_startup_system_to_stage("startup-pipeline", render_4d::init_render_pipeline)
         .add_startup_system_to_stage("startup-pipeline", render_3d::init_render_pipeline)
-        .add_startup_system_to_stage("startup-finish", init_world_data)
-        .add_system(update_window_size.before("update-surface"))
-        .add_system(update_surface.label("update-surface"))
         .add_system(update_world.label("update-world"))
+        .add_system(fog::update_uniform_system.before("update-uniforms-3d"))
+        .add_system(depth_palette::update_uniform_system.before("update-uniforms-3d"))
+        .add_system(light::update_uniform_system.before("update-uniforms-3d"))
+        .add_system(background::update_background.before("update-uniforms-3d"))
         .add_system(
             uniform_4d::update_uniform_buffer
                 .label("update-uniforms-4d")
                 .after("camera-4d")
-                .after("update-surface"),
+                .after("update-surface")
+                .after("update-world"),
         )
         .add_system(
             uniform_3d::update_uniform_buffer
                 .label("update-uniforms-3d")
                 .after("camera-3d")
-                .after("update-surface"),
+                .after("update-surface")
+                .after("update-world"),
         )
         .add_system(
             render_4d::render
                 .label("render-4d")
                 .after("update-uniforms-4d")
                 .after("update-world"),
-        )
-        .add_system(
-            render_3d::render
-                .label("render-3d")
-                .after("update-uniforms-3d")
-                .after("render-4d"),
         );
-    app.run();
 }
 
-fn init_world_data(mut world: ResMut<World>) {
-    let normal_type = world.insert_type(VoxelType::new(Srgb::new(0.212, 0.247, 0.278)));
+/// Loads the world at `world_path`. If there is none, generates terrain with `generator` if a
+/// seed was given and builds the default scene otherwise.
+fn load_world(world_path: &Path, terrain_seed: Option<u32>, generator: Generator) -> World {
+    if world_path.exists() {
+        return World::load(world_path).expect("Failed to load world");
+    }
+    let mut world = World::new(88);
+    match terrain_seed {
+        Some(seed) => generator.generate(&mut world, seed),
+        None => build_world_data(&mut world),
+    }
+    world
+}
 
-    for i in 10..40 {
-        for j in 35..60 {
-            for k in 35..55 {
-                for l in 10..75 {
-                    world[Vector4::new(i, j, k, l)] = normal_type;
-                }
-            }
+/// Combines the world with the worlds given by `--union`, `--intersection`, `--difference`
+/// and `--xor`, in that order, with their voxels taking precedence. Goes through the history
+/// so that it can be undone.
+fn combine_worlds(args: &[String], world: &mut World, history: &mut History) {
+    let operations = [
+        ("--union", Operation::Union),
+        ("--intersection", Operation::Intersection),
+        ("--difference", Operation::Difference),
+        ("--xor", Operation::Xor),
+    ];
+    for (flag, operation) in operations {
+        if let Some(path) = flag_value(args, flag) {
+            let other = World::load(path).expect("Failed to load world to combine with");
+            let region = world.bounds();
+            history
+                .apply(
+                    world,
+                    region,
+                    Operand::World(&other),
+                    operation,
+                    Priority::Operand,
+                )
+                .expect("Failed to combine worlds");
         }
     }
-    for i in 20..70 {
-        for j in 16..40 {
-            for k in 16..25 {
-                for l in 16..40 {
-                    world[Vector4::new(i, j, k, l)] = normal_type;
-                }
-            }
-        }
+}
+
+/// Imports the `.vox` model given by `--import`. `--import-mode` chooses how it becomes 4D and
+/// `--import-at x,y,z,w` where its origin goes.
+fn import_model(args: &[String], world: &mut World) {
+    let path = match flag_value(args, "--import") {
+        Some(path) => path,
+        None => return,
+    };
+    let vox = VoxFile::load(path).expect("Failed to load .vox model");
+    let mode = flag_value(args, "--import-mode").map_or(Import::Stack, |mode| {
+        Import::parse(mode).expect("Import mode must be extrude:<length>, revolve or stack")
+    });
+    let origin = flag_value(args, "--import-at").map_or(Vector4::zeros(), |origin| {
+        parse_position(origin).expect("Import origin must be given as <x>,<y>,<z>,<w>")
+    });
+    let placement = Placement {
+        origin,
+        ..Default::default()
+    };
+    vox::import(world, &vox, &placement, mode).expect("Failed to import .vox model");
+}
+
+/// Renders the world at `size` pixels from the initial cameras, as a PNG if `path` ends in
+/// `.png` and as a PPM otherwise.
+fn render_headless(path: &str, size: Vector2<u32>, world: World) {
+    let world_size = WorldSize(world.size());
+    let pixels = headless::render_still(
+        world,
+        camera_3d::Camera::new(Vector3::new(4.0, 4.0, 4.0), 0.0),
+        camera_4d::Camera::new(world_size),
+        ViewSize(128),
+        WindowSize(size),
+        headless::Shading::default(),
+    )
+    .expect("Failed to render");
+    if path.ends_with(".png") {
+        utils::write_png(path, &pixels).expect("Failed to write image");
+    } else {
+        utils::write_ppm(path, &pixels).expect("Failed to write image");
     }
 }
+
+/// Exports the view of the world as seen from the initial 4D camera.
+fn export_view(path: &str, world: World) {
+    let format = export::Format::from_path(path).expect("Failed to export view");
+    let camera = camera_4d::Camera::new(WorldSize(world.size())).to_internal();
+    let view = render_4d::trace_view_cpu(&world, &camera, ViewSize(128));
+    export::export(path, format, &view.voxels, world.types()).expect("Failed to export view");
+}
+
+fn build_world_data(world: &mut World) {
+    let normal_type = world.insert_type(VoxelType::new(
+        Srgb::new(0.212, 0.247, 0.278),
+        1.0,
+        0.0,
+        1.0,
+    ));
+
+    shapes::draw(
+        world,
+        &Region::new(Vector4::new(10, 35, 35, 10), Vector4::new(40, 60, 55, 75)),
+        normal_type,
+    );
+    shapes::draw(
+        world,
+        &Region::new(Vector4::new(20, 16, 16, 16), Vector4::new(70, 40, 25, 40)),
+        normal_type,
+    );
+}
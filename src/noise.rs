@@ -0,0 +1,190 @@
+//! Deterministic 4D noise. The same seed and point always give the same value.
+
+use nalgebra::Vector4;
+
+pub trait Noise {
+    fn sample(&self, point: Vector4<f32>) -> f32;
+}
+
+/// Mixes a seed and a lattice cell into a pseudo random number.
+fn hash(seed: u32, cell: Vector4<i32>) -> u32 {
+    let mut h = seed.wrapping_mul(0x9e37_79b9);
+    for &x in cell.iter() {
+        h = (h ^ x as u32).wrapping_mul(0x85eb_ca6b);
+        h ^= h >> 13;
+    }
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^ (h >> 16)
+}
+
+/// One of the 32 vectors from the center of a tesseract to the middle of one of its edges.
+fn gradient(hash: u32) -> Vector4<f32> {
+    let zero = (hash >> 3) % 4;
+    let mut out = Vector4::zeros();
+    let mut bit = 0;
+    for i in 0..4 {
+        if i != zero as usize {
+            out[i] = if hash >> bit & 1 == 0 { 1.0 } else { -1.0 };
+            bit += 1;
+        }
+    }
+    out
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn floor(point: Vector4<f32>) -> Vector4<i32> {
+    point.map(|x| x.floor() as i32)
+}
+
+/// Gradient noise on a square lattice, roughly between -1 and 1.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Perlin {
+    pub seed: u32,
+}
+
+impl Noise for Perlin {
+    fn sample(&self, point: Vector4<f32>) -> f32 {
+        let cell = floor(point);
+        let local = point - cell.cast();
+        let weights = local.map(fade);
+        let mut value = 0.0;
+        for corner in 0..16 {
+            let offset = Vector4::new(
+                corner & 1,
+                corner >> 1 & 1,
+                corner >> 2 & 1,
+                corner >> 3 & 1,
+            );
+            let gradient = gradient(hash(self.seed, cell + offset));
+            let weight: f32 = (0..4)
+                .map(|i| {
+                    if offset[i] == 1 {
+                        weights[i]
+                    } else {
+                        1.0 - weights[i]
+                    }
+                })
+                .product();
+            value += weight * gradient.dot(&(local - offset.cast()));
+        }
+        value
+    }
+}
+
+/// Gradient noise on a simplex lattice, roughly between -1 and 1. Cheaper than [`Perlin`] in
+/// 4D and without its axis aligned artifacts.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Simplex {
+    pub seed: u32,
+}
+
+impl Noise for Simplex {
+    fn sample(&self, point: Vector4<f32>) -> f32 {
+        let sqrt_5 = 5f32.sqrt();
+        let skew = (sqrt_5 - 1.0) / 4.0;
+        let unskew = (5.0 - sqrt_5) / 20.0;
+
+        let cell = floor(point + Vector4::repeat(point.sum() * skew));
+        let origin = cell.cast::<f32>() - Vector4::repeat(cell.sum() as f32 * unskew);
+        let local = point - origin;
+
+        // How many of the other coordinates each coordinate is larger than, which decides the
+        // order the simplex's corners are visited in.
+        let mut rank = Vector4::<i32>::zeros();
+        for i in 0..4 {
+            for j in i + 1..4 {
+                if local[i] > local[j] {
+                    rank[i] += 1;
+                } else {
+                    rank[j] += 1;
+                }
+            }
+        }
+
+        let mut value = 0.0;
+        for corner in 0..5 {
+            let offset = rank.map(|x| (x >= 4 - corner) as i32);
+            let to_corner = local - offset.cast() + Vector4::repeat(corner as f32 * unskew);
+            let t = 0.6 - to_corner.norm_squared();
+            if t > 0.0 {
+                let gradient = gradient(hash(self.seed, cell + offset));
+                value += t.powi(4) * gradient.dot(&to_corner);
+            }
+        }
+        27.0 * value
+    }
+}
+
+/// The distance to the closest of a set of scattered points, one in every lattice cell.
+/// Between 0 and about 1.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Worley {
+    pub seed: u32,
+}
+
+impl Worley {
+    fn feature_point(&self, cell: Vector4<i32>) -> Vector4<f32> {
+        let h = hash(self.seed, cell);
+        let offset = Vector4::new(h, h >> 8, h >> 16, h >> 24).map(|x| (x & 0xff) as f32 / 256.0);
+        cell.cast() + offset
+    }
+}
+
+impl Noise for Worley {
+    fn sample(&self, point: Vector4<f32>) -> f32 {
+        let cell = floor(point);
+        let mut closest = f32::INFINITY;
+        for i in 0..81 {
+            let offset = Vector4::new(i % 3, i / 3 % 3, i / 9 % 3, i / 27) - Vector4::repeat(1);
+            let distance = (self.feature_point(cell + offset) - point).norm_squared();
+            closest = closest.min(distance);
+        }
+        closest.sqrt()
+    }
+}
+
+/// Fractal noise, summing octaves of `noise` that each have `lacunarity` times the frequency
+/// and `gain` times the amplitude of the previous one. Stays in the range of `noise`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Fbm<N> {
+    pub noise: N,
+    pub octaves: u32,
+    pub lacunarity: f32,
+    pub gain: f32,
+}
+
+impl<N> Fbm<N> {
+    pub fn new(noise: N, octaves: u32) -> Self {
+        Fbm {
+            noise,
+            octaves,
+            lacunarity: 2.0,
+            gain: 0.5,
+        }
+    }
+}
+
+impl<N: Noise> Noise for Fbm<N> {
+    fn sample(&self, point: Vector4<f32>) -> f32 {
+        let mut value = 0.0;
+        let mut total = 0.0;
+        let mut frequency = 1.0;
+        let mut amplitude = 1.0;
+        for octave in 0..self.octaves {
+            // Shifts every octave so that their lattices don't line up at the origin.
+            let shift = Vector4::repeat(octave as f32 * 17.31);
+            value += amplitude * self.noise.sample(point * frequency + shift);
+            total += amplitude;
+            frequency *= self.lacunarity;
+            amplitude *= self.gain;
+        }
+        if total > 0.0 {
+            value / total
+        } else {
+            0.0
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use crate::camera_3d;
+use crate::camera_4d::CameraInternal;
+use crate::render_3d::{generate_ray, intersect_view, step_mask};
+use crate::render_4d::{hit_world, WorldHit};
+use crate::surface::{DeviceResource, QueueResource};
+use crate::utils::sign;
+use crate::view::{ViewSize, ViewTexture};
+use crate::voxel::VoxelId;
+use crate::world::World;
+use crate::{uniform_3d, uniform_4d};
+use bevy::prelude::*;
+use nalgebra::{Vector2, Vector3};
+use ndarray::Array3;
+
+/// A voxel found by casting a ray from the screen.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Pick {
+    /// The view voxel the ray hit.
+    pub view_position: Vector3<u32>,
+    /// The face of the view voxel the ray entered through, as a unit vector.
+    pub view_normal: Vector3<i32>,
+    /// The world voxel the view voxel shows.
+    pub world: WorldHit,
+}
+
+/// The voxel under the cursor, or at the center of the screen while the 3D camera has the
+/// cursor, as of the last time a mouse button was pressed.
+#[derive(Resource, Copy, Clone, Debug, Default, PartialEq)]
+pub struct PickedVoxel(pub Option<Pick>);
+
+/// The view voxels as they were last read back from the GPU for picking.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct ViewVoxels(pub Option<Array3<VoxelId>>);
+
+/// Casts a ray through a pixel like `3d.frag`, returning the first view voxel it hits along
+/// with the world voxel it shows. `view` holds the view voxels the 4D camera traced, and
+/// `frag_coord` is in physical pixels from the top left of the window.
+pub fn pick(
+    world: &World,
+    view: &Array3<VoxelId>,
+    uniforms_3d: &uniform_3d::Uniforms,
+    camera_4d: &CameraInternal,
+    frag_coord: Vector2<f32>,
+) -> Option<Pick> {
+    let view_size = ViewSize(view.shape()[0] as u32);
+    let size = Vector3::repeat(view_size.0 as i32);
+    let mut ray = generate_ray(uniforms_3d, frag_coord);
+
+    let (t_enter, t_exit, entry_face) = intersect_view(size.cast(), &ray);
+    if t_enter >= t_exit || t_exit < 0.0 {
+        return None;
+    }
+
+    let mut mask = Vector3::zeros();
+
+    // Rays starting outside of the view volume start on its surface instead.
+    if t_enter > 0.0 {
+        mask = entry_face;
+        ray.origin += ray.direction * t_enter;
+    }
+
+    let mut voxel_pos = ray
+        .origin
+        .map(|x| x.floor() as i32)
+        .zip_map(&(size - Vector3::repeat(1)), |x, max| x.clamp(0, max));
+
+    let delta_dist = ray.direction.map(|x| (1.0 / x).abs());
+
+    let ray_sign = ray.direction.map(sign);
+    let ray_step = ray_sign.map(|x| x as i32);
+
+    let mut side_dist = (ray_sign.component_mul(&(voxel_pos.cast::<f32>() - ray.origin))
+        + ray_sign * 0.5
+        + Vector3::repeat(0.5))
+    .component_mul(&delta_dist);
+
+    for _ in 0..view_size.0 * 3 {
+        if (0..3).any(|i| voxel_pos[i] < 0 || voxel_pos[i] >= size[i]) {
+            break;
+        }
+        let view_position = voxel_pos.map(|x| x as u32);
+        if view[[
+            voxel_pos.x as usize,
+            voxel_pos.y as usize,
+            voxel_pos.z as usize,
+        ]] != World::solid_air()
+        {
+            let view_normal = if mask == Vector3::zeros() {
+                let axis = ray.direction.iamax();
+                Vector3::ith(axis, -sign(ray.direction[axis]) as i32)
+            } else {
+                -mask.component_mul(&ray_step)
+            };
+            // Only the voxel that was hit is traced back into the world.
+            return hit_world(world, camera_4d, view_size, view_position).map(|hit| Pick {
+                view_position,
+                view_normal,
+                world: hit,
+            });
+        }
+
+        mask = step_mask(side_dist);
+
+        // Multiplying by the mask would make the distances along axes the ray is parallel to
+        // NaN, as those deltas are infinite.
+        for i in (0..3).filter(|&i| mask[i] != 0) {
+            side_dist[i] += delta_dist[i];
+        }
+        voxel_pos += mask.component_mul(&ray_step);
+    }
+    None
+}
+
+/// Reads the view back from the GPU when a mouse button is pressed, which waits for it to
+/// finish rendering, so it isn't done every frame.
+pub fn read_view_system(
+    btn: Res<Input<MouseButton>>,
+    device: Res<DeviceResource>,
+    queue: Res<QueueResource>,
+    view_texture: Res<ViewTexture>,
+    mut view: ResMut<ViewVoxels>,
+) {
+    if btn.get_just_pressed().next().is_none() {
+        return;
+    }
+    view.0 = match view_texture.read(&device, &queue) {
+        Ok(voxels) => Some(voxels),
+        Err(err) => {
+            eprintln!("Failed to read the view for picking: {}", err);
+            None
+        }
+    };
+}
+
+/// Picks whenever the view is read back, with the cameras as they were last rendered, so
+/// this runs before they move.
+pub fn picking_system(
+    windows: Res<Windows>,
+    camera_3d: Res<camera_3d::Camera>,
+    world: Res<World>,
+    view: Res<ViewVoxels>,
+    uniforms_3d: Res<uniform_3d::Uniforms>,
+    uniforms_4d: Res<uniform_4d::Uniforms>,
+    mut picked: ResMut<PickedVoxel>,
+) {
+    if !view.is_changed() {
+        return;
+    }
+    let window = windows.get_primary().unwrap();
+    let window_size = uniforms_3d.window_size;
+    let frag_coord = if camera_3d.active {
+        Some(window_size / 2.0)
+    } else if let Some(cursor) = window.cursor_position() {
+        // Bevy measures the cursor in logical pixels from the bottom left.
+        let cursor = Vector2::new(cursor.x, cursor.y) * window.scale_factor() as f32;
+        Some(Vector2::new(cursor.x, window_size.y - cursor.y))
+    } else {
+        None
+    };
+    let pick = frag_coord
+        .zip(view.0.as_ref())
+        .and_then(|(frag_coord, view)| {
+            pick(&world, view, &uniforms_3d, &uniforms_4d.camera, frag_coord)
+        });
+    // Only changes the resource when the pick changes, so that systems can react to that.
+    if picked.0 != pick {
+        picked.0 = pick;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera_4d;
+    use crate::render_4d::trace_view_cpu;
+    use crate::voxel::test_types;
+    use crate::world::WorldSize;
+    use nalgebra::Vector4;
+    use std::f32::consts::{FRAC_PI_2, PI};
+
+    const WINDOW_SIZE: Vector2<f32> = Vector2::new(64.0, 48.0);
+
+    /// A world of size 8 with one voxel, seen by the initial 4D camera through a view of the
+    /// same size. That camera looks along world Z, with view voxel `(x, y, z)` showing the
+    /// column of world voxels at `(z, x, _, y)`, so the voxel is in view voxel `(2, 3, 1)`.
+    fn scene() -> (World, camera_4d::CameraInternal, Array3<VoxelId>) {
+        let mut world = World::new(8);
+        let stone = world.insert_type(test_types::stone());
+        world.set(Vector4::new(1, 2, 5, 3), stone);
+        let camera = camera_4d::Camera::new(WorldSize(8)).to_internal();
+        let view = trace_view_cpu(&world, &camera, ViewSize(8)).voxels;
+        (world, camera, view)
+    }
+
+    fn uniforms(camera: camera_3d::Camera) -> uniform_3d::Uniforms {
+        uniform_3d::Uniforms::new(
+            camera.to_internal(),
+            WINDOW_SIZE,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            [Default::default(); 256],
+        )
+    }
+
+    /// Picks through the center of the window with the 3D camera at `position`, turned by
+    /// `x` about Z and pitched by `y` from straight up.
+    fn pick_center(position: Vector3<f32>, x: f32, y: f32) -> Option<Pick> {
+        let (world, camera_4d, view) = scene();
+        let mut camera = camera_3d::Camera::new(position, x);
+        camera.y = y;
+        pick(
+            &world,
+            &view,
+            &uniforms(camera),
+            &camera_4d,
+            WINDOW_SIZE / 2.0,
+        )
+    }
+
+    #[test]
+    fn picks_the_voxel_through_the_face_seen() {
+        let hit = WorldHit {
+            position: Vector4::new(1, 2, 5, 3),
+            // The 4D camera sees the voxel from low Z.
+            normal: Vector4::new(0, 0, -1, 0),
+        };
+        assert_eq!(
+            pick_center(Vector3::new(-5.0, 3.5, 1.5), 0.0, FRAC_PI_2),
+            Some(Pick {
+                view_position: Vector3::new(2, 3, 1),
+                view_normal: Vector3::new(-1, 0, 0),
+                world: hit,
+            })
+        );
+        assert_eq!(
+            pick_center(Vector3::new(2.5, 3.5, 12.0), 0.0, PI - 0.01),
+            Some(Pick {
+                view_position: Vector3::new(2, 3, 1),
+                view_normal: Vector3::new(0, 0, 1),
+                world: hit,
+            })
+        );
+    }
+
+    #[test]
+    fn rays_that_miss_pick_nothing() {
+        // Looking away from the view, and through it next to the voxel.
+        assert_eq!(
+            pick_center(Vector3::new(-5.0, 3.5, 1.5), PI, FRAC_PI_2),
+            None
+        );
+        assert_eq!(
+            pick_center(Vector3::new(-5.0, 4.5, 1.5), 0.0, FRAC_PI_2),
+            None
+        );
+    }
+}
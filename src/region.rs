@@ -0,0 +1,65 @@
+use nalgebra::Vector4;
+
+/// An axis aligned 4D box of voxel positions. `min` is inclusive and `max` is exclusive.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub min: Vector4<u32>,
+    pub max: Vector4<u32>,
+}
+
+impl Region {
+    pub fn new(min: Vector4<u32>, max: Vector4<u32>) -> Self {
+        Region { min, max }
+    }
+
+    /// The region covering a world of the given size.
+    pub fn cube(size: u32) -> Self {
+        Region::new(Vector4::zeros(), Vector4::repeat(size))
+    }
+
+    pub fn point(position: Vector4<u32>) -> Self {
+        Region::new(position, position + Vector4::repeat(1))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min
+            .iter()
+            .zip(self.max.iter())
+            .any(|(min, max)| min >= max)
+    }
+
+    pub fn contains(&self, position: Vector4<u32>) -> bool {
+        (0..4).all(|i| self.min[i] <= position[i] && position[i] < self.max[i])
+    }
+
+    pub fn intersection(&self, other: &Region) -> Region {
+        Region::new(self.min.sup(&other.min), self.max.inf(&other.max))
+    }
+
+    /// The smallest region containing both regions.
+    pub fn union(&self, other: &Region) -> Region {
+        if self.is_empty() {
+            *other
+        } else if other.is_empty() {
+            *self
+        } else {
+            Region::new(self.min.inf(&other.min), self.max.sup(&other.max))
+        }
+    }
+
+    /// Whether the regions overlap or share a face, edge or corner.
+    pub fn touches(&self, other: &Region) -> bool {
+        (0..4).all(|i| self.min[i] <= other.max[i] && other.min[i] <= self.max[i])
+    }
+
+    /// Every position in the region, with `w` varying fastest.
+    pub fn positions(self) -> impl Iterator<Item = Vector4<u32>> {
+        let Region { min, max } = self;
+        (min.x..max.x).flat_map(move |x| {
+            (min.y..max.y).flat_map(move |y| {
+                (min.z..max.z)
+                    .flat_map(move |z| (min.w..max.w).map(move |w| Vector4::new(x, y, z, w)))
+            })
+        })
+    }
+}
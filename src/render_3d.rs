@@ -1,10 +1,22 @@
+use crate::background::{Background, BackgroundBindGroup};
+use crate::depth_palette::ColorMode;
+use crate::headless::OffscreenTarget;
+use crate::render_4d::TracedView;
+use crate::screenshot::{channel_order, Capture, Screenshot};
 use crate::surface::{DeviceResource, QueueResource, SurfaceConfigResource, SurfaceResource};
-use crate::uniform_3d::UniformBindGroup;
-use crate::utils::to_u32_array;
+use crate::uniform_3d::{UniformBindGroup, Uniforms};
+use crate::utils::{sign, to_u32_array};
 use crate::view::View3dBindGroup;
+use crate::voxel::VoxelId;
+use crate::world::World;
+use bevy::ecs::system::SystemParam;
 use bevy::prelude::*;
 use bytemuck::cast_slice;
+use nalgebra::{Vector2, Vector3, Vector4};
+use ndarray::{arr1, Array3, Axis, Zip};
+use palette::{LinSrgb, Srgb};
 use std::borrow::Cow;
+use std::marker::PhantomData;
 use wgpu::util::DeviceExt;
 use wgpu::*;
 
@@ -29,6 +41,7 @@ pub fn init_render_pipeline(
     device: Res<DeviceResource>,
     uniform_bind_group: Res<UniformBindGroup>,
     view_bind_group: Res<View3dBindGroup>,
+    background_bind_group: Res<BackgroundBindGroup>,
     surface_config: Res<SurfaceConfigResource>,
 ) {
     let vert = unsafe {
@@ -52,7 +65,11 @@ pub fn init_render_pipeline(
 
     let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
         label: Some("render-3d-pipeline-layout"),
-        bind_group_layouts: &[&uniform_bind_group.1, &view_bind_group.1],
+        bind_group_layouts: &[
+            &uniform_bind_group.1,
+            &view_bind_group.1,
+            &background_bind_group.1,
+        ],
         push_constant_ranges: &[],
     });
 
@@ -87,17 +104,52 @@ pub fn init_render_pipeline(
     commands.insert_resource(VertexBuffer(vertex_buffer));
 }
 
+/// The pipeline and bindings the 3D pass draws with.
+#[derive(SystemParam)]
+pub struct DrawResources<'w, 's> {
+    render_pipeline: Res<'w, Render3dPipeline>,
+    uniform_bind_group: Res<'w, UniformBindGroup>,
+    view_3d_bind_group: Res<'w, View3dBindGroup>,
+    background_bind_group: Res<'w, BackgroundBindGroup>,
+    vertex_buffer: Res<'w, VertexBuffer>,
+    #[system_param(ignore)]
+    _marker: PhantomData<&'s ()>,
+}
+
+fn draw(encoder: &mut CommandEncoder, view: &TextureView, resources: &DrawResources) {
+    let mut render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
+        label: Some("render-3d-pass"),
+        color_attachments: &[Some(RenderPassColorAttachment {
+            view,
+            resolve_target: None,
+            ops: Operations {
+                load: LoadOp::Clear(Color {
+                    r: 0.0,
+                    g: 0.0,
+                    b: 0.0,
+                    a: 1.0,
+                }),
+                store: true,
+            },
+        })],
+        depth_stencil_attachment: None,
+    });
+    render_pass.set_pipeline(&resources.render_pipeline.0);
+    render_pass.set_bind_group(0, &resources.uniform_bind_group.0, &[]);
+    render_pass.set_bind_group(1, &resources.view_3d_bind_group.0, &[]);
+    render_pass.set_bind_group(2, &resources.background_bind_group.0, &[]);
+    render_pass.set_vertex_buffer(0, resources.vertex_buffer.0.slice(..));
+    render_pass.draw(0..6, 0..1);
+}
+
 pub fn render(
     device: Res<DeviceResource>,
     queue: Res<QueueResource>,
     surface: Res<SurfaceResource>,
     surface_config: Res<SurfaceConfigResource>,
-    render_pipeline: Res<Render3dPipeline>,
-    uniform_bind_group: Res<UniformBindGroup>,
-    view_3d_bind_group: Res<View3dBindGroup>,
-    vertex_buffer: Res<VertexBuffer>,
+    resources: DrawResources,
+    mut screenshot: ResMut<Screenshot>,
 ) {
-    let vertex_buffer = &vertex_buffer.0;
     let frame = match surface.get_current_texture() {
         Ok(frame) => frame,
         Err(err) => {
@@ -121,32 +173,408 @@ pub fn render(
         label: Some("render-3d-encoder"),
     });
 
-    {
-        let mut render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
-            label: Some("render-3d-pass"),
-            color_attachments: &[Some(RenderPassColorAttachment {
-                view: &view,
-                resolve_target: None,
-                ops: Operations {
-                    load: LoadOp::Clear(Color {
-                        r: 0.0,
-                        g: 0.0,
-                        b: 0.0,
-                        a: 1.0,
-                    }),
-                    store: true,
-                },
-            })],
-            depth_stencil_attachment: None,
-        });
-        render_pass.set_pipeline(&render_pipeline.0);
-        render_pass.set_bind_group(0, &uniform_bind_group.0, &[]);
-        render_pass.set_bind_group(1, &view_3d_bind_group.0, &[]);
-        render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
-        render_pass.draw(0..6, 0..1);
+    draw(&mut encoder, &view, &resources);
+
+    if screenshot.requested {
+        screenshot.requested = false;
+        if channel_order(surface_config.format).is_some() {
+            // Draws the frame again into a texture that can be copied from.
+            let size = Vector2::new(surface_config.width, surface_config.height);
+            let texture = Capture::create_texture(&device, size, surface_config.format);
+            draw(
+                &mut encoder,
+                &texture.create_view(&TextureViewDescriptor::default()),
+                &resources,
+            );
+            screenshot.capture = Some(Capture::new(
+                &device,
+                &mut encoder,
+                &texture,
+                size,
+                surface_config.format,
+            ));
+        } else {
+            eprintln!(
+                "Can't take a screenshot of a {:?} surface",
+                surface_config.format
+            );
+        }
     }
 
     queue.submit(std::iter::once(encoder.finish()));
 
     frame.present();
 }
+
+/// Renders into the [`OffscreenTarget`] instead of the surface and copies the frame into its
+/// readback buffer.
+pub fn render_offscreen(
+    device: Res<DeviceResource>,
+    queue: Res<QueueResource>,
+    target: Res<OffscreenTarget>,
+    resources: DrawResources,
+) {
+    let view = target.texture.create_view(&TextureViewDescriptor {
+        label: Some("offscreen-texture-view"),
+        ..Default::default()
+    });
+    let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+        label: Some("render-3d-offscreen-encoder"),
+    });
+
+    draw(&mut encoder, &view, &resources);
+    target.copy_to_buffer(&mut encoder);
+
+    queue.submit(std::iter::once(encoder.finish()));
+}
+
+/// Once the accumulated color is this opaque, nothing behind it is visible anymore.
+const MAX_ALPHA: f32 = 0.99;
+
+pub struct Ray {
+    pub origin: Vector3<f32>,
+    pub direction: Vector3<f32>,
+}
+
+/// Runs the ray cast from `3d.frag` on the CPU over a view volume produced by
+/// [`crate::render_4d::trace_view_cpu`]. Returns sRGB pixels indexed by `(y, x, channel)`,
+/// with the first row at the top of the screen, as they would be read back from the surface.
+pub fn render_cpu(view: &TracedView, uniforms: &Uniforms, background: &Background) -> Array3<u8> {
+    let width = uniforms.window_size.x as usize;
+    let height = uniforms.window_size.y as usize;
+    let mut out = Array3::zeros((height, width, 4));
+    Zip::indexed(out.lanes_mut(Axis(2))).for_each(|(y, x), mut pixel| {
+        let frag_coord = Vector2::new(x as f32 + 0.5, y as f32 + 0.5);
+        let color: Srgb<u8> =
+            Srgb::from_linear(shade_pixel(view, uniforms, background, frag_coord)).into_format();
+        pixel.assign(&arr1(&[color.red, color.green, color.blue, 255]));
+    });
+    out
+}
+
+/// The ray through a pixel, with `frag_coord` in pixels from the top left of the window.
+pub fn generate_ray(uniforms: &Uniforms, frag_coord: Vector2<f32>) -> Ray {
+    let window_size = uniforms.window_size;
+    let pixel_ndc = frag_coord.component_div(&window_size);
+    let mut pixel_camera = pixel_ndc * 2.0 - Vector2::repeat(1.0);
+    pixel_camera.x *= window_size.x / window_size.y;
+    pixel_camera *= uniforms.camera.tan_half_fov;
+
+    let inv_rotation = uniforms.camera.inv_rotation.fixed_slice::<3, 3>(0, 0);
+    let unnorm_dir = inv_rotation * pixel_camera.push(1.0);
+    Ray {
+        origin: uniforms.camera.position,
+        direction: unnorm_dir.normalize(),
+    }
+}
+
+/// Fetches a view voxel, returning air outside of the view like an out of bounds `texelFetch`.
+fn get_texel(view: &Array3<VoxelId>, location: Vector3<i32>) -> VoxelId {
+    if location.iter().any(|&x| x < 0) {
+        return World::air();
+    }
+    let location = location.map(|x| x as usize);
+    view.get([location.x, location.y, location.z])
+        .copied()
+        .unwrap_or_else(World::air)
+}
+
+/// Fetches the 4D depth of a view voxel, returning zero outside of the view like `get_texel`.
+fn get_depth(depth: &Array3<f32>, location: Vector3<i32>) -> f32 {
+    if location.iter().any(|&x| x < 0) {
+        return 0.0;
+    }
+    let location = location.map(|x| x as usize);
+    depth
+        .get([location.x, location.y, location.z])
+        .copied()
+        .unwrap_or(0.0)
+}
+
+fn get_tint(traced: &TracedView, location: Vector3<i32>) -> Vector4<f32> {
+    if location.iter().any(|&x| x < 0) {
+        return Vector4::zeros();
+    }
+    let location = location.map(|x| x as usize);
+    traced
+        .tint
+        .get([location.x, location.y, location.z])
+        .copied()
+        .unwrap_or_else(Vector4::zeros)
+}
+
+fn contains_voxel(view: &Array3<VoxelId>, location: Vector3<i32>) -> bool {
+    get_texel(view, location) != World::solid_air()
+}
+
+/// The ray parameters where the ray enters and leaves the view volume, which are the wrong way
+/// around if the ray misses it, and the axis of the face the ray enters through.
+pub fn intersect_view(size: Vector3<f32>, ray: &Ray) -> (f32, f32, Vector3<i32>) {
+    let t0 = (-ray.origin).component_div(&ray.direction);
+    let t1 = (size - ray.origin).component_div(&ray.direction);
+    let t_min = t0.zip_map(&t1, f32::min);
+    let t_max = t0.zip_map(&t1, f32::max);
+    let t_enter = t_min.max();
+    let entry_face = t_min.map(|x| (x == t_enter) as i32);
+    (t_enter, t_max.min(), entry_face)
+}
+
+fn view_size(view: &Array3<VoxelId>) -> Vector3<i32> {
+    let shape = view.shape();
+    Vector3::new(shape[0], shape[1], shape[2]).cast()
+}
+
+/// Whether a point on the surface of the view volume is close to two of its faces.
+fn on_view_bounds(view: &Array3<VoxelId>, uniforms: &Uniforms, point: Vector3<f32>) -> bool {
+    let distance = point.zip_map(&(view_size(view).cast::<f32>() - point), f32::min);
+    let width = uniforms.background.bounds_width;
+    distance.iter().filter(|&&x| x < width).count() >= 2
+}
+
+fn shade_pixel(
+    traced: &TracedView,
+    uniforms: &Uniforms,
+    background: &Background,
+    frag_coord: Vector2<f32>,
+) -> LinSrgb {
+    let view = &traced.voxels;
+    let mut ray = generate_ray(uniforms, frag_coord);
+
+    let (t_enter, t_exit, entry_face) = intersect_view(view_size(view).cast(), &ray);
+    if t_enter >= t_exit || t_exit < 0.0 {
+        return uniforms.background.color(background, ray.direction);
+    }
+    let exit_point = ray.origin + ray.direction * t_exit;
+    // The front of the bounds covers everything inside the view volume.
+    if t_enter > 0.0 && on_view_bounds(view, uniforms, ray.origin + ray.direction * t_enter) {
+        return uniforms.background.bounds_color;
+    }
+
+    let mut mask = Vector3::zeros();
+
+    // Rays starting outside of the view volume start on its surface instead.
+    if t_enter > 0.0 {
+        mask = entry_face;
+        ray.origin += ray.direction * t_enter;
+    }
+
+    let mut voxel_pos = ray
+        .origin
+        .map(|x| x.floor() as i32)
+        .zip_map(&(view_size(view) - Vector3::repeat(1)), |x, max| {
+            x.clamp(0, max)
+        });
+
+    let delta_dist = ray.direction.map(|x| (1.0 / x).abs());
+
+    let ray_sign = ray.direction.map(sign);
+    let ray_step = ray_sign.map(|x| x as i32);
+
+    let mut side_dist = (ray_sign.component_mul(&(voxel_pos.cast::<f32>() - ray.origin))
+        + ray_sign * 0.5
+        + Vector3::repeat(0.5))
+    .component_mul(&delta_dist);
+
+    // Premultiplied color of the translucent view voxels passed through so far.
+    let mut color = Vector4::<f32>::zeros();
+
+    for _ in 0..128 * 3 {
+        if !in_view(view, voxel_pos) {
+            break;
+        }
+        let tint = get_tint(traced, voxel_pos);
+        if tint.w > 0.0 {
+            let normal = face_normal(mask, ray_step, ray.direction);
+            let position = face_center(voxel_pos, normal);
+            let ao = face_ambient_occlusion(uniforms, view, voxel_pos, mask, ray_step);
+            let diffuse = uniforms
+                .lights
+                .light_face(
+                    position,
+                    normal,
+                    -ray.direction,
+                    1.0,
+                    ao,
+                    |origin, dir, max| trace_shadow(traced, origin, dir, max),
+                )
+                .diffuse;
+            color += Vector4::new(
+                tint.x * diffuse.red,
+                tint.y * diffuse.green,
+                tint.z * diffuse.blue,
+                tint.w,
+            ) * (1.0 - color.w);
+        }
+        if contains_voxel(view, voxel_pos) || color.w >= MAX_ALPHA {
+            break;
+        }
+
+        mask = step_mask(side_dist);
+
+        side_dist += mask.cast::<f32>().component_mul(&delta_dist);
+        voxel_pos += mask.component_mul(&ray_step);
+    }
+
+    let behind = if in_view(view, voxel_pos) && contains_voxel(view, voxel_pos) {
+        let normal = face_normal(mask, ray_step, ray.direction);
+        let ao = face_ambient_occlusion(uniforms, view, voxel_pos, mask, ray_step);
+        shade_voxel(traced, uniforms, voxel_pos, normal, -ray.direction, ao)
+    } else if on_view_bounds(view, uniforms, exit_point) {
+        uniforms.background.bounds_color
+    } else {
+        uniforms.background.color(background, ray.direction)
+    };
+    LinSrgb::new(color.x, color.y, color.z) + behind * (1.0 - color.w)
+}
+
+fn in_view(view: &Array3<VoxelId>, location: Vector3<i32>) -> bool {
+    let shape = view.shape();
+    (0..3).all(|i| location[i] >= 0 && (location[i] as usize) < shape[i])
+}
+
+/// How much of the light travelling from `origin` along `direction` arrives, after passing
+/// through translucent voxels, up to `max_distance` away.
+fn trace_shadow(
+    traced: &TracedView,
+    origin: Vector3<f32>,
+    direction: Vector3<f32>,
+    max_distance: f32,
+) -> f32 {
+    let view = &traced.voxels;
+    let mut voxel_pos = origin.map(|x| x.floor() as i32);
+    let delta_dist = direction.map(|x| (1.0 / x).abs());
+    let ray_sign = direction.map(sign);
+    let ray_step = ray_sign.map(|x| x as i32);
+    let mut side_dist = (ray_sign.component_mul(&(voxel_pos.cast::<f32>() - origin))
+        + ray_sign * 0.5
+        + Vector3::repeat(0.5))
+    .component_mul(&delta_dist);
+
+    let mut transmittance = 1.0;
+
+    for _ in 0..128 * 3 {
+        if !in_view(view, voxel_pos) {
+            break;
+        }
+        if contains_voxel(view, voxel_pos) {
+            return 0.0;
+        }
+        transmittance *= 1.0 - get_tint(traced, voxel_pos).w;
+        if transmittance <= 1.0 - MAX_ALPHA {
+            return 0.0;
+        }
+        if side_dist.min() > max_distance {
+            break;
+        }
+
+        let mask = step_mask(side_dist);
+
+        side_dist += mask.cast::<f32>().component_mul(&delta_dist);
+        voxel_pos += mask.component_mul(&ray_step);
+    }
+    transmittance
+}
+
+/// `lessThanEqual(side_dist.xyz, min(side_dist.yzx, side_dist.zxy))`
+pub fn step_mask(s: Vector3<f32>) -> Vector3<i32> {
+    Vector3::new(
+        s.x <= s.y.min(s.z),
+        s.y <= s.z.min(s.x),
+        s.z <= s.x.min(s.y),
+    )
+    .map(|x| x as i32)
+}
+
+fn occlusion(view: &Array3<VoxelId>, location: Vector3<i32>) -> f32 {
+    if in_view(view, location) && contains_voxel(view, location) {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+/// Darkens faces by how many of the voxels around the one in front of them are filled, so that
+/// corners get less ambient light. `normal` has to point along an axis.
+fn ambient_occlusion(view: &Array3<VoxelId>, location: Vector3<i32>, normal: Vector3<i32>) -> f32 {
+    let front = location + normal;
+    let abs = normal.abs();
+    let u = Vector3::new(abs.y, abs.z, abs.x);
+    let v = Vector3::new(abs.z, abs.x, abs.y);
+    let edges = occlusion(view, front + u)
+        + occlusion(view, front - u)
+        + occlusion(view, front + v)
+        + occlusion(view, front - v);
+    let corners = occlusion(view, front + u + v)
+        + occlusion(view, front + u - v)
+        + occlusion(view, front - u + v)
+        + occlusion(view, front - u - v);
+    1.0 - 0.5 * (edges + 0.5 * corners) / 6.0
+}
+
+/// The ambient occlusion of the face the ray entered the current voxel through, if it is
+/// enabled.
+fn face_ambient_occlusion(
+    uniforms: &Uniforms,
+    view: &Array3<VoxelId>,
+    location: Vector3<i32>,
+    mask: Vector3<i32>,
+    ray_step: Vector3<i32>,
+) -> f32 {
+    if uniforms.lights.ambient_occlusion == 0 || mask == Vector3::zeros() {
+        1.0
+    } else {
+        ambient_occlusion(view, location, -mask.component_mul(&ray_step))
+    }
+}
+
+/// The normal of the face the ray entered the current voxel through, or facing the camera if
+/// the ray has not stepped yet.
+fn face_normal(
+    mask: Vector3<i32>,
+    ray_step: Vector3<i32>,
+    direction: Vector3<f32>,
+) -> Vector3<f32> {
+    if mask == Vector3::zeros() {
+        -direction
+    } else {
+        -mask.component_mul(&ray_step).cast::<f32>().normalize()
+    }
+}
+
+/// The center of the face of the voxel at `location` facing along `normal`.
+fn face_center(location: Vector3<i32>, normal: Vector3<f32>) -> Vector3<f32> {
+    location.cast::<f32>() + Vector3::repeat(0.5) + normal * 0.5
+}
+
+fn shade_voxel(
+    traced: &TracedView,
+    uniforms: &Uniforms,
+    voxel_pos: Vector3<i32>,
+    normal: Vector3<f32>,
+    to_camera: Vector3<f32>,
+    ao: f32,
+) -> LinSrgb {
+    let depth = get_depth(&traced.depth, voxel_pos);
+    let voxel = uniforms.voxel_types[get_texel(&traced.voxels, voxel_pos).0 as usize];
+    let depth_mode = uniforms.depth_palette.mode == ColorMode::Depth as u32;
+    let albedo = if depth_mode {
+        uniforms.depth_palette.color(depth)
+    } else {
+        voxel.color
+    };
+    let lighting = uniforms.lights.light_face(
+        face_center(voxel_pos, normal),
+        normal,
+        to_camera,
+        voxel.roughness,
+        ao,
+        |origin, direction, max_distance| trace_shadow(traced, origin, direction, max_distance),
+    );
+    let emissive = LinSrgb::new(voxel.emissive, voxel.emissive, voxel.emissive);
+    let color = albedo * (lighting.diffuse + emissive) + lighting.specular;
+    if depth_mode {
+        color
+    } else {
+        uniforms.fog.apply(color, depth)
+    }
+}
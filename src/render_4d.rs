@@ -1,10 +1,13 @@
+use crate::camera_4d::{CameraInternal, Projection};
 use crate::surface::{DeviceResource, QueueResource};
 use crate::uniform_4d::UniformBindGroup;
-use crate::utils::to_u32_array;
-use crate::view::View4dBindGroup;
-use crate::world::{WorldBindGroup, WorldSize};
+use crate::utils::{sign, to_u32_array};
+use crate::view::{View4dBindGroup, ViewSize};
+use crate::voxel::{VoxelId, VoxelTypeInternal};
+use crate::world::{World, WorldBindGroup};
 use bevy::prelude::*;
-use nalgebra::Vector3;
+use nalgebra::{Vector3, Vector4};
+use ndarray::Array3;
 use std::borrow::Cow;
 use wgpu::*;
 
@@ -48,7 +51,7 @@ pub fn init_render_pipeline(
 }
 
 pub fn render(
-    world_size: Res<WorldSize>,
+    view_size: Res<ViewSize>,
     device: Res<DeviceResource>,
     queue: Res<QueueResource>,
     render_pipeline: Res<Render4dPipeline>,
@@ -70,9 +73,376 @@ pub fn render(
         render_pass.set_bind_group(0, &uniform_bind_group.0, &[]);
         render_pass.set_bind_group(1, &world_bind_group.0, &[]);
         render_pass.set_bind_group(2, &view_bind_group.0, &[]);
-        let workgroup_counts = Vector3::repeat(world_size.0).component_div(&LOCAL_WORKGROUP_SIZE);
+        let workgroup_counts = Vector3::repeat(view_size.0).component_div(&LOCAL_WORKGROUP_SIZE);
         render_pass.dispatch_workgroups(workgroup_counts.x, workgroup_counts.y, workgroup_counts.z);
     }
 
     queue.submit(std::iter::once(encoder.finish()));
 }
+
+struct Ray {
+    origin: Vector4<f32>,
+    direction: Vector4<f32>,
+}
+
+/// Once the tint is this opaque, nothing behind it is visible anymore.
+const MAX_TINT_ALPHA: f32 = 0.99;
+
+/// The textures written by `4d.comp`, indexed by view texture coordinates.
+pub struct TracedView {
+    pub voxels: Array3<VoxelId>,
+    /// How far the ray travelled before hitting each voxel, in world voxels.
+    pub depth: Array3<f32>,
+    /// The translucent voxels in front of each voxel as a premultiplied color.
+    pub tint: Array3<Vector4<f32>>,
+}
+
+#[derive(Copy, Clone)]
+struct TracedVoxel {
+    voxel: VoxelId,
+    depth: f32,
+    tint: Vector4<f32>,
+}
+
+impl TracedVoxel {
+    fn new(voxel: VoxelId, depth: f32) -> Self {
+        TracedVoxel {
+            voxel,
+            depth,
+            tint: Vector4::zeros(),
+        }
+    }
+}
+
+/// Runs the ray march from `4d.comp` on the CPU, producing the same view volume as the compute
+/// shader.
+pub fn trace_view_cpu(world: &World, camera: &CameraInternal, view_size: ViewSize) -> TracedView {
+    let size = view_size.0 as usize;
+    let types = world.types_internal();
+    let traced = Array3::from_shape_fn((size, size, size), |(x, y, z)| {
+        let id = Vector3::new(x, y, z).cast();
+        if camera.projection == Projection::CrossSection as u32 {
+            slice_voxel(world, &types, camera, view_size, id)
+        } else {
+            trace_ray(world, &types, camera, view_size, id)
+        }
+    });
+    TracedView {
+        voxels: traced.map(|x| x.voxel),
+        depth: traced.map(|x| x.depth),
+        tint: traced.map(|x| x.tint),
+    }
+}
+
+fn generate_ray(camera: &CameraInternal, view_size: ViewSize, id: Vector3<u32>) -> Ray {
+    let view_size = Vector3::repeat(view_size.0 as f32);
+    let voxel_centered = (id.cast::<f32>() + Vector3::repeat(0.5)) - view_size / 2.0;
+    if camera.projection == Projection::Perspective as u32 {
+        let offset = voxel_centered.component_div(&(view_size / 2.0)) * camera.tan_half_fov;
+        Ray {
+            origin: camera.position,
+            direction: camera.rotation * offset.push(1.0).normalize(),
+        }
+    } else {
+        let offset = voxel_centered * camera.voxel_size;
+        Ray {
+            origin: camera.position + camera.rotation * offset.push(0.0),
+            direction: camera.rotation * Vector4::new(0.0, 0.0, 0.0, 1.0),
+        }
+    }
+}
+
+/// Moves the ray origin into the world, returning how far it was moved, or `None` if the ray
+/// misses the world.
+fn update_ray_intersection(ray: &mut Ray, world_size: u32) -> Option<f32> {
+    let t0 = (Vector4::repeat(1.0) - ray.origin).component_div(&ray.direction);
+    let t1 = (Vector4::repeat((world_size - 1) as f32) - ray.origin).component_div(&ray.direction);
+    let t_min = t0.zip_map(&t1, f32::min).max().max(0.0);
+    let t_max = t0.zip_map(&t1, f32::max).min();
+    if t_min >= t_max {
+        None
+    } else {
+        let t = t_min + 0.3;
+        ray.origin += ray.direction * t;
+        Some(t)
+    }
+}
+
+/// Blends a translucent voxel behind the voxels already in `tint`.
+fn blend_tint(tint: &mut Vector4<f32>, ty: &VoxelTypeInternal) {
+    let color = Vector4::new(ty.color.red, ty.color.green, ty.color.blue, 1.0);
+    *tint += color * (1.0 - tint.w) * ty.opacity;
+}
+
+/// Where a march along a ray stopped.
+struct March {
+    /// Texel location of the voxel the march stopped in.
+    location: Vector4<i32>,
+    /// The axis of the face the ray entered that voxel through, or zero if it started there.
+    mask: Vector4<i32>,
+    ray_step: Vector4<i32>,
+    /// How far the ray travelled before entering that voxel.
+    depth: f32,
+}
+
+/// Marches the ray of a view voxel through the world, calling `stop` with every voxel until it
+/// returns true. Returns `None` if the ray misses the world.
+fn march(
+    world: &World,
+    camera: &CameraInternal,
+    view_size: ViewSize,
+    id: Vector3<u32>,
+    mut stop: impl FnMut(VoxelId) -> bool,
+) -> Option<March> {
+    let mut ray = generate_ray(camera, view_size, id);
+
+    let depth = update_ray_intersection(&mut ray, world.size() + 2)?;
+
+    let mut voxel_pos = ray.origin.map(|x| x.floor() as i32);
+
+    let delta_dist = (ray.direction + Vector4::repeat(f32::EPSILON)).map(|x| (1.0 / x).abs());
+
+    let ray_sign = ray.direction.map(sign);
+    let ray_step = ray_sign.map(|x| x as i32);
+
+    let mut side_dist = (ray_sign.component_mul(&(voxel_pos.cast::<f32>() - ray.origin))
+        + ray_sign * 0.5
+        + Vector4::repeat(0.5))
+    .component_mul(&delta_dist);
+
+    let mut mask = Vector4::zeros();
+
+    for _ in 0..128 * 3 {
+        if stop(world.get_texel(voxel_pos)) {
+            break;
+        }
+
+        mask = step_mask(side_dist);
+
+        side_dist += mask.cast::<f32>().component_mul(&delta_dist);
+        voxel_pos += mask.component_mul(&ray_step);
+    }
+
+    // The side that was stepped over last is where the ray entered the voxel.
+    let entry = mask
+        .cast::<f32>()
+        .component_mul(&(side_dist - delta_dist))
+        .max();
+    Some(March {
+        location: voxel_pos,
+        mask,
+        ray_step,
+        depth: depth + entry,
+    })
+}
+
+fn trace_ray(
+    world: &World,
+    types: &[VoxelTypeInternal; 256],
+    camera: &CameraInternal,
+    view_size: ViewSize,
+    id: Vector3<u32>,
+) -> TracedVoxel {
+    let mut tint = Vector4::zeros();
+    // Stays solid air if the ray leaves the world or the tint becomes opaque first.
+    let mut voxel = World::solid_air();
+
+    let march = march(world, camera, view_size, id, |current| {
+        let ty = &types[current.0 as usize];
+        if current == World::solid_air() || ty.is_opaque() {
+            voxel = current;
+            return true;
+        }
+        if current != World::air() {
+            blend_tint(&mut tint, ty);
+        }
+        tint.w >= MAX_TINT_ALPHA
+    });
+    match march {
+        Some(march) => TracedVoxel {
+            voxel,
+            depth: march.depth,
+            tint,
+        },
+        None => TracedVoxel::new(World::solid_air(), 0.0),
+    }
+}
+
+fn slice_voxel(
+    world: &World,
+    types: &[VoxelTypeInternal; 256],
+    camera: &CameraInternal,
+    view_size: ViewSize,
+    id: Vector3<u32>,
+) -> TracedVoxel {
+    let ray = generate_ray(camera, view_size, id);
+    let voxel = world.get_texel(ray.origin.map(|x| x.floor() as i32));
+    let ty = &types[voxel.0 as usize];
+    if voxel.0 > 1 && !ty.is_opaque() {
+        let mut traced = TracedVoxel::new(World::solid_air(), 0.0);
+        blend_tint(&mut traced.tint, ty);
+        traced
+    } else if voxel == World::air() {
+        TracedVoxel::new(World::solid_air(), 0.0)
+    } else {
+        TracedVoxel::new(voxel, 0.0)
+    }
+}
+
+/// `lessThanEqual(side_dist.xyzw, min(side_dist.yzwx, min(side_dist.zwxy, side_dist.wxyz)))`
+fn step_mask(s: Vector4<f32>) -> Vector4<i32> {
+    Vector4::new(
+        s.x <= s.y.min(s.z.min(s.w)),
+        s.y <= s.z.min(s.w.min(s.x)),
+        s.z <= s.w.min(s.x.min(s.y)),
+        s.w <= s.x.min(s.y.min(s.z)),
+    )
+    .map(|x| x as i32)
+}
+
+/// A world voxel seen through a view voxel.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WorldHit {
+    pub position: Vector4<u32>,
+    /// The face of the voxel that is seen, as a unit vector along one of the world axes.
+    pub normal: Vector4<i32>,
+}
+
+/// The first voxel that isn't air along the ray of a view voxel, translucent or not, or `None`
+/// if there is no such voxel.
+pub fn hit_world(
+    world: &World,
+    camera: &CameraInternal,
+    view_size: ViewSize,
+    id: Vector3<u32>,
+) -> Option<WorldHit> {
+    let ray = generate_ray(camera, view_size, id);
+    let (location, normal) = if camera.projection == Projection::CrossSection as u32 {
+        // The slice is seen from the side the camera looks at it from.
+        (
+            ray.origin.map(|x| x.floor() as i32),
+            snap_to_axis(-ray.direction),
+        )
+    } else {
+        let march = march(world, camera, view_size, id, |voxel| voxel != World::air())?;
+        let normal = if march.mask == Vector4::zeros() {
+            snap_to_axis(-ray.direction)
+        } else {
+            -march.mask.component_mul(&march.ray_step)
+        };
+        (march.location, normal)
+    };
+    // Solid air is the border around the world, and air is outside of it.
+    let voxel = world.get_texel(location);
+    if voxel == World::air() || voxel == World::solid_air() {
+        return None;
+    }
+    Some(WorldHit {
+        position: texel_to_world(location),
+        normal: Vector4::new(normal.w, normal.z, normal.y, normal.x),
+    })
+}
+
+/// Converts a location in the shader's padded and reversed coordinates to a `World` index.
+fn texel_to_world(location: Vector4<i32>) -> Vector4<u32> {
+    Vector4::new(location.w, location.z, location.y, location.x).map(|x| x as u32 - 1)
+}
+
+/// The unit vector along the axis closest to `v`.
+fn snap_to_axis(v: Vector4<f32>) -> Vector4<i32> {
+    let axis = v.iamax();
+    Vector4::ith(axis, sign(v[axis]) as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera_4d::{Camera, Projection};
+    use crate::voxel::VoxelType;
+    use crate::world::WorldSize;
+    use palette::Srgb;
+
+    #[test]
+    fn single_voxel_in_front_of_camera() {
+        let mut world = World::new(8);
+        let id = world.insert_type(VoxelType::new(Srgb::new(1.0, 0.0, 0.0), 1.0, 0.0, 1.0));
+        world.set(Vector4::new(1, 2, 5, 3), id);
+        let camera = Camera::new(WorldSize(8)).to_internal();
+
+        // The default camera looks along world Z from Z = 0, with view voxel `(x, y, z)`
+        // seeing the column of world voxels at `(z, x, _, y)`.
+        let view = trace_view_cpu(&world, &camera, ViewSize(8));
+        for ((x, y, z), &voxel) in view.voxels.indexed_iter() {
+            if (x, y, z) == (2, 3, 1) {
+                assert_eq!(voxel, id);
+            } else {
+                assert_eq!(voxel, World::solid_air(), "at {:?}", (x, y, z));
+            }
+        }
+        // The camera is on the solid air border, five voxels in front of the voxel's face.
+        assert!((view.depth[[2, 3, 1]] - 5.0).abs() < 1e-4);
+        assert_eq!(view.tint[[2, 3, 1]], Vector4::zeros());
+    }
+
+    #[test]
+    fn translucent_voxel_tints_the_voxel_behind() {
+        let mut world = World::new(8);
+        let glass = world.insert_type(VoxelType::new(Srgb::new(0.0, 0.0, 1.0), 0.5, 0.0, 1.0));
+        let solid = world.insert_type(VoxelType::new(Srgb::new(1.0, 0.0, 0.0), 1.0, 0.0, 1.0));
+        world.set(Vector4::new(0, 0, 2, 0), glass);
+        world.set(Vector4::new(0, 0, 6, 0), solid);
+        let camera = Camera::new(WorldSize(8)).to_internal();
+
+        let view = trace_view_cpu(&world, &camera, ViewSize(8));
+        assert_eq!(view.voxels[[0, 0, 0]], solid);
+        assert_eq!(view.tint[[0, 0, 0]], Vector4::new(0.0, 0.0, 0.5, 0.5));
+    }
+
+    #[test]
+    fn perspective_rays_diverge() {
+        let mut world = World::new(8);
+        let id = world.insert_type(VoxelType::new(Srgb::new(1.0, 0.0, 0.0), 1.0, 0.0, 1.0));
+        // At the far side of the world, off the view axis in every direction.
+        world.set(Vector4::new(1, 1, 7, 1), id);
+        let mut camera = Camera::new(WorldSize(8));
+
+        let orthographic = trace_view_cpu(&world, &camera.to_internal(), ViewSize(8));
+        camera.projection = Projection::Perspective;
+        let perspective = trace_view_cpu(&world, &camera.to_internal(), ViewSize(8));
+
+        // The parallel ray of the view voxel in front of the voxel hits it, but the rays
+        // spreading out from the camera reach it from a view voxel closer to the center.
+        assert_eq!(orthographic.voxels[[1, 1, 1]], id);
+        assert_eq!(orthographic.voxels[[2, 2, 2]], World::solid_air());
+        assert_eq!(perspective.voxels[[1, 1, 1]], World::solid_air());
+        assert_eq!(perspective.voxels[[2, 2, 2]], id);
+    }
+
+    #[test]
+    fn cross_section_shows_only_the_sliced_voxels() {
+        let mut world = World::new(8);
+        let id = world.insert_type(VoxelType::new(Srgb::new(1.0, 0.0, 0.0), 1.0, 0.0, 1.0));
+        let glass = world.insert_type(VoxelType::new(Srgb::new(0.0, 0.0, 1.0), 0.5, 0.0, 1.0));
+        world.set(Vector4::new(1, 2, 5, 3), id);
+        world.set(Vector4::new(2, 2, 5, 3), glass);
+        // Behind the slice, where a ray would see it.
+        world.set(Vector4::new(0, 2, 6, 3), id);
+        let mut camera = Camera::new(WorldSize(8));
+        camera.projection = Projection::CrossSection;
+        // Through the middle of the voxels at world Z = 5, along the view W axis.
+        camera.position.y = 5.5;
+
+        let view = trace_view_cpu(&world, &camera.to_internal(), ViewSize(8));
+        for ((x, y, z), &voxel) in view.voxels.indexed_iter() {
+            if (x, y, z) == (2, 3, 1) {
+                assert_eq!(voxel, id);
+            } else {
+                // Translucent voxels only tint what is behind them, and there is nothing
+                // behind a slice.
+                assert_eq!(voxel, World::solid_air(), "at {:?}", (x, y, z));
+            }
+        }
+        assert_eq!(view.tint[[2, 3, 2]], Vector4::new(0.0, 0.0, 0.5, 0.5));
+        assert_eq!(view.tint[[2, 3, 0]], Vector4::zeros());
+    }
+}
@@ -0,0 +1,158 @@
+//! Saving frames as PNG files.
+//!
+//! The surface format is whatever `init_surface` picked from the formats the adapter supports,
+//! which is BGRA on most desktop platforms, so captured pixels are swizzled to RGBA before they
+//! are written.
+
+use crate::headless::OffscreenTarget;
+use crate::surface::DeviceResource;
+use crate::utils::{map_read, write_png};
+use bevy::prelude::*;
+use nalgebra::Vector2;
+use ndarray::Array3;
+use std::io;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use wgpu::*;
+
+/// Where each RGBA channel is in a pixel of the format, for the 8 bit formats the surface and
+/// offscreen targets use.
+pub fn channel_order(format: TextureFormat) -> Option<[usize; 4]> {
+    match format {
+        TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => Some([0, 1, 2, 3]),
+        TextureFormat::Bgra8Unorm | TextureFormat::Bgra8UnormSrgb => Some([2, 1, 0, 3]),
+        _ => None,
+    }
+}
+
+/// Maps a buffer holding a texture copied with [`OffscreenTarget::padded_bytes_per_row`],
+/// blocking until the copy has finished. Returns RGBA pixels indexed by `(y, x, channel)`.
+/// Panics if the format isn't supported by [`channel_order`].
+pub fn read_pixels(
+    device: &Device,
+    buffer: &Buffer,
+    size: Vector2<u32>,
+    format: TextureFormat,
+) -> io::Result<Array3<u8>> {
+    let order = channel_order(format).expect("Unsupported texture format");
+    let slice = buffer.slice(..);
+    map_read(device, &slice)?;
+
+    let padded_bytes_per_row = OffscreenTarget::padded_bytes_per_row(size) as usize;
+    let bytes_per_row = size.x as usize * 4;
+    let mut pixels = Vec::with_capacity(bytes_per_row * size.y as usize);
+    for row in slice.get_mapped_range().chunks(padded_bytes_per_row) {
+        for pixel in row[..bytes_per_row].chunks(4) {
+            pixels.extend(order.iter().map(|&i| pixel[i]));
+        }
+    }
+    buffer.unmap();
+
+    Ok(Array3::from_shape_vec((size.y as usize, size.x as usize, 4), pixels).unwrap())
+}
+
+/// A frame on its way from a texture to a buffer that can be read.
+pub struct Capture {
+    buffer: Buffer,
+    size: Vector2<u32>,
+    format: TextureFormat,
+}
+
+impl Capture {
+    /// A texture to draw a frame into for capturing it. The surface can't be copied from
+    /// directly, as not every platform allows [`TextureUsages::COPY_SRC`] on it.
+    pub fn create_texture(device: &Device, size: Vector2<u32>, format: TextureFormat) -> Texture {
+        device.create_texture(&TextureDescriptor {
+            label: Some("screenshot-texture"),
+            size: OffscreenTarget::extent(size),
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
+        })
+    }
+
+    /// Records a copy of `texture` into a new buffer. The texture needs
+    /// [`TextureUsages::COPY_SRC`] and a format supported by [`channel_order`].
+    pub fn new(
+        device: &Device,
+        encoder: &mut CommandEncoder,
+        texture: &Texture,
+        size: Vector2<u32>,
+        format: TextureFormat,
+    ) -> Self {
+        let padded_bytes_per_row = OffscreenTarget::padded_bytes_per_row(size);
+        let buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("screenshot-buffer"),
+            size: (padded_bytes_per_row * size.y) as u64,
+            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        encoder.copy_texture_to_buffer(
+            ImageCopyTexture {
+                texture,
+                mip_level: 0,
+                origin: Origin3d::ZERO,
+                aspect: TextureAspect::All,
+            },
+            ImageCopyBuffer {
+                buffer: &buffer,
+                layout: ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(padded_bytes_per_row),
+                    rows_per_image: NonZeroU32::new(size.y),
+                },
+            },
+            OffscreenTarget::extent(size),
+        );
+        Capture {
+            buffer,
+            size,
+            format,
+        }
+    }
+
+    pub fn read(&self, device: &Device) -> io::Result<Array3<u8>> {
+        read_pixels(device, &self.buffer, self.size, self.format)
+    }
+}
+
+/// A screenshot that was asked for, and the frame captured for it by `render_3d::render`.
+#[derive(Resource, Default)]
+pub struct Screenshot {
+    pub requested: bool,
+    pub capture: Option<Capture>,
+}
+
+/// `screenshot-<milliseconds since the Unix epoch>.png`, which sorts by the time it was taken.
+pub fn timestamped_path() -> PathBuf {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    PathBuf::from(format!("screenshot-{}.png", time.as_millis()))
+}
+
+/// Asks for the next frame to be captured with F12.
+pub fn request_screenshot_system(key: Res<Input<KeyCode>>, mut screenshot: ResMut<Screenshot>) {
+    if key.just_pressed(KeyCode::F12) {
+        screenshot.requested = true;
+    }
+}
+
+/// Writes the captured frame, if there is one, to a timestamped PNG in the working directory.
+pub fn save_screenshot_system(device: Res<DeviceResource>, mut screenshot: ResMut<Screenshot>) {
+    let capture = match screenshot.capture.take() {
+        Some(capture) => capture,
+        None => return,
+    };
+    let path = timestamped_path();
+    let result = capture
+        .read(&device)
+        .and_then(|pixels| write_png(&path, &pixels));
+    match result {
+        Ok(()) => println!("Saved a screenshot to {}", path.display()),
+        Err(err) => eprintln!("Failed to save the screenshot: {}", err),
+    }
+}
@@ -0,0 +1,328 @@
+//! Solids that can be drawn into a [`World`].
+//!
+//! Shapes are in world index coordinates, where the voxel at `p` covers `p` to `p + 1`. A
+//! voxel belongs to a shape if its center does.
+
+use crate::region::Region;
+use crate::voxel::VoxelId;
+use crate::world::World;
+use nalgebra::{Matrix4, Vector2, Vector4};
+
+pub trait Shape {
+    /// The corners of a box containing the shape. These may be infinite.
+    fn bounds(&self) -> (Vector4<f32>, Vector4<f32>);
+
+    fn contains(&self, point: Vector4<f32>) -> bool;
+
+    /// The voxels of the world that may be in the shape.
+    fn region(&self, world_size: u32) -> Region {
+        let (min, max) = self.bounds();
+        let clamp = |x: f32| x.clamp(0.0, world_size as f32) as u32;
+        Region::new(min.map(|x| clamp(x.floor())), max.map(|x| clamp(x.ceil())))
+    }
+}
+
+pub fn voxel_center(position: Vector4<u32>) -> Vector4<f32> {
+    position.cast() + Vector4::repeat(0.5)
+}
+
+/// The voxels of the world in the shape, in the order of [`Region::positions`] over `region`,
+/// with the voxels outside of the shape left as they are. Also returns how many are in it.
+pub fn rasterize(
+    world: &World,
+    shape: &impl Shape,
+    region: Region,
+    id: VoxelId,
+) -> (Vec<VoxelId>, u64) {
+    let mut count = 0;
+    let voxels = region
+        .positions()
+        .map(|position| {
+            if shape.contains(voxel_center(position)) {
+                count += 1;
+                id
+            } else {
+                world[position]
+            }
+        })
+        .collect();
+    (voxels, count)
+}
+
+/// Sets every voxel in the shape to `id`, clipped to the bounds of the world. Returns the
+/// number of voxels in the shape, including those that already were `id`.
+pub fn draw(world: &mut World, shape: &impl Shape, id: VoxelId) -> u64 {
+    let region = shape.region(world.size());
+    let (voxels, count) = rasterize(world, shape, region, id);
+    world.write_region(region, voxels);
+    count
+}
+
+impl Shape for Region {
+    fn bounds(&self) -> (Vector4<f32>, Vector4<f32>) {
+        (self.min.cast(), self.max.cast())
+    }
+
+    fn contains(&self, point: Vector4<f32>) -> bool {
+        (0..4).all(|i| self.min[i] as f32 <= point[i] && point[i] < self.max[i] as f32)
+    }
+}
+
+/// A 4D ball, whose surface is a 3-sphere.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Hypersphere {
+    pub center: Vector4<f32>,
+    pub radius: f32,
+}
+
+impl Shape for Hypersphere {
+    fn bounds(&self) -> (Vector4<f32>, Vector4<f32>) {
+        let radius = Vector4::repeat(self.radius);
+        (self.center - radius, self.center + radius)
+    }
+
+    fn contains(&self, point: Vector4<f32>) -> bool {
+        (point - self.center).norm_squared() <= self.radius * self.radius
+    }
+}
+
+/// The surface of a tesseract, `thickness` deep.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TesseractShell {
+    pub center: Vector4<f32>,
+    /// Half of the side length.
+    pub half_size: f32,
+    pub thickness: f32,
+}
+
+impl Shape for TesseractShell {
+    fn bounds(&self) -> (Vector4<f32>, Vector4<f32>) {
+        let half_size = Vector4::repeat(self.half_size);
+        (self.center - half_size, self.center + half_size)
+    }
+
+    fn contains(&self, point: Vector4<f32>) -> bool {
+        let distance = (point - self.center).amax();
+        distance <= self.half_size && distance > self.half_size - self.thickness
+    }
+}
+
+/// A 3D ball extruded along one of the axes, which is the 4D analogue of a cylinder.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Cylinder {
+    center: Vector4<f32>,
+    /// The index of the axis the ball is extruded along.
+    axis: usize,
+    radius: f32,
+    /// Half of the length along `axis`.
+    half_length: f32,
+}
+
+impl Cylinder {
+    /// Panics if `axis` isn't one of the four axes.
+    pub fn new(center: Vector4<f32>, axis: usize, radius: f32, half_length: f32) -> Self {
+        assert!(axis < 4, "Invalid cylinder axis {}", axis);
+        Cylinder {
+            center,
+            axis,
+            radius,
+            half_length,
+        }
+    }
+}
+
+impl Shape for Cylinder {
+    fn bounds(&self) -> (Vector4<f32>, Vector4<f32>) {
+        let mut extent = Vector4::repeat(self.radius);
+        extent[self.axis] = self.half_length;
+        (self.center - extent, self.center + extent)
+    }
+
+    fn contains(&self, point: Vector4<f32>) -> bool {
+        let mut offset = point - self.center;
+        let along = offset[self.axis];
+        offset[self.axis] = 0.0;
+        along.abs() <= self.half_length && offset.norm_squared() <= self.radius * self.radius
+    }
+}
+
+/// The product of a disk in the XY plane and a disk in the ZW plane.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Duocylinder {
+    pub center: Vector4<f32>,
+    /// The radius in the XY plane and the radius in the ZW plane.
+    pub radii: Vector2<f32>,
+}
+
+impl Shape for Duocylinder {
+    fn bounds(&self) -> (Vector4<f32>, Vector4<f32>) {
+        let extent = Vector4::new(self.radii.x, self.radii.x, self.radii.y, self.radii.y);
+        (self.center - extent, self.center + extent)
+    }
+
+    fn contains(&self, point: Vector4<f32>) -> bool {
+        let offset = point - self.center;
+        let xy = Vector2::new(offset.x, offset.y);
+        let zw = Vector2::new(offset.z, offset.w);
+        xy.norm_squared() <= self.radii.x * self.radii.x
+            && zw.norm_squared() <= self.radii.y * self.radii.y
+    }
+}
+
+/// The convex hull of five points, the 4D analogue of a tetrahedron.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Simplex {
+    vertices: [Vector4<f32>; 5],
+    /// Maps a point relative to the first vertex to its weights for the other vertices, or
+    /// `None` if the vertices lie in a hyperplane and the simplex is empty.
+    inverse: Option<Matrix4<f32>>,
+}
+
+impl Simplex {
+    pub fn new(vertices: [Vector4<f32>; 5]) -> Self {
+        let edges = Matrix4::from_columns(&[
+            vertices[1] - vertices[0],
+            vertices[2] - vertices[0],
+            vertices[3] - vertices[0],
+            vertices[4] - vertices[0],
+        ]);
+        Simplex {
+            vertices,
+            inverse: edges.try_inverse(),
+        }
+    }
+
+    /// The simplex with all edges the same length whose vertices are `radius` away from
+    /// `center`.
+    pub fn regular(center: Vector4<f32>, radius: f32) -> Self {
+        // The unit vectors along the axes and a fifth point as far from each of them as they
+        // are from each other.
+        let t = (1.0 - 5.0f32.sqrt()) / 4.0;
+        let points = [
+            Vector4::x(),
+            Vector4::y(),
+            Vector4::z(),
+            Vector4::w(),
+            Vector4::repeat(t),
+        ];
+        let centroid = Vector4::repeat((1.0 + t) / 5.0);
+        let scale = radius / (points[0] - centroid).norm();
+        Simplex::new(points.map(|x| center + (x - centroid) * scale))
+    }
+}
+
+impl Shape for Simplex {
+    fn bounds(&self) -> (Vector4<f32>, Vector4<f32>) {
+        let first = self.vertices[0];
+        self.vertices[1..]
+            .iter()
+            .fold((first, first), |(min, max), x| (min.inf(x), max.sup(x)))
+    }
+
+    fn contains(&self, point: Vector4<f32>) -> bool {
+        match self.inverse {
+            Some(inverse) => {
+                let weights = inverse * (point - self.vertices[0]);
+                weights.iter().all(|&x| x >= 0.0) && weights.sum() <= 1.0
+            }
+            None => false,
+        }
+    }
+}
+
+/// Everything on the side of a hyperplane that `normal` points away from.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct HalfSpace {
+    /// A point on the hyperplane.
+    pub point: Vector4<f32>,
+    pub normal: Vector4<f32>,
+}
+
+impl Shape for HalfSpace {
+    fn bounds(&self) -> (Vector4<f32>, Vector4<f32>) {
+        (
+            Vector4::repeat(f32::NEG_INFINITY),
+            Vector4::repeat(f32::INFINITY),
+        )
+    }
+
+    fn contains(&self, point: Vector4<f32>) -> bool {
+        (point - self.point).dot(&self.normal) <= 0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The number of voxels of a world of size 8 in the shape.
+    fn count(shape: &impl Shape) -> u64 {
+        draw(&mut World::new(8), shape, World::solid_air())
+    }
+
+    #[test]
+    fn shapes_contain_the_expected_voxels() {
+        let center = Vector4::repeat(4.0);
+        let region = Region::new(Vector4::new(1, 2, 3, 4), Vector4::new(3, 5, 7, 9));
+        // Clipped to the world along `w`.
+        assert_eq!(count(&region), 2 * 3 * 4 * 4);
+        assert_eq!(
+            count(&Hypersphere {
+                center,
+                radius: 2.0
+            }),
+            80
+        );
+        assert_eq!(
+            count(&TesseractShell {
+                center,
+                half_size: 2.0,
+                thickness: 1.0,
+            }),
+            4u64.pow(4) - 2u64.pow(4)
+        );
+        assert_eq!(count(&Cylinder::new(center, 3, 1.5, 2.0)), 8 * 4);
+        assert_eq!(
+            count(&Duocylinder {
+                center,
+                radii: Vector2::new(1.5, 2.5),
+            }),
+            4 * 16
+        );
+        let corner = Simplex::new([
+            Vector4::zeros(),
+            Vector4::x() * 4.0,
+            Vector4::y() * 4.0,
+            Vector4::z() * 4.0,
+            Vector4::w() * 4.0,
+        ]);
+        assert_eq!(count(&corner), 15);
+        assert_eq!(
+            count(&HalfSpace {
+                point: Vector4::new(3.0, 3.0, 0.0, 0.0),
+                normal: Vector4::new(1.0, 1.0, 0.0, 0.0),
+            }),
+            1344
+        );
+    }
+
+    #[test]
+    fn regular_simplex_has_equal_edges() {
+        let center = Vector4::new(4.0, 3.0, 2.0, 1.0);
+        let simplex = Simplex::regular(center, 3.0);
+        let edge = (simplex.vertices[0] - simplex.vertices[1]).norm();
+        for (i, a) in simplex.vertices.iter().enumerate() {
+            assert!(((a - center).norm() - 3.0).abs() < 1e-4);
+            for b in &simplex.vertices[i + 1..] {
+                assert!(((a - b).norm() - edge).abs() < 1e-4);
+            }
+        }
+        assert!(simplex.contains(center));
+    }
+
+    #[test]
+    #[should_panic]
+    fn cylinder_axis_must_exist() {
+        Cylinder::new(Vector4::zeros(), 4, 1.0, 1.0);
+    }
+}
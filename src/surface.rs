@@ -1,5 +1,4 @@
 use crate::window_size::WindowSize;
-use crate::world::WorldSize;
 use bevy::prelude::*;
 use bevy::winit::WinitWindows;
 use futures::executor::block_on;
@@ -71,7 +70,6 @@ pub fn init_surface(
     winit_windows: NonSend<WinitWindows>,
     windows: Res<Windows>,
     window_size: Res<WindowSize>,
-    world_size: Res<WorldSize>,
 ) {
     let window = winit_windows
         .get_window(windows.get_primary().unwrap().id())
@@ -85,20 +83,10 @@ pub fn init_surface(
     }))
     .expect("Failed to find an appropriate adapter");
 
-    let (device, queue) = block_on(adapter.request_device(
-        &DeviceDescriptor {
-            label: Some("device"),
-            features: Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES
-                | Features::SPIRV_SHADER_PASSTHROUGH,
-            limits: Limits {
-                max_texture_dimension_3d: (world_size.0 + 2) * (world_size.0 + 2),
-                ..Default::default()
-            },
-        },
-        None,
-    ))
-    .expect("Failed to create device");
+    let (device, queue) = block_on(request_device(&adapter)).expect("Failed to create device");
 
+    // Only render attachments are allowed on every platform, so screenshots are drawn into a
+    // texture of their own instead of being copied out of the surface.
     let config = SurfaceConfiguration {
         usage: TextureUsages::RENDER_ATTACHMENT,
         format: surface.get_supported_formats(&adapter)[0],
@@ -115,6 +103,43 @@ pub fn init_surface(
     commands.insert_resource(QueueResource(queue));
 }
 
+async fn request_device(adapter: &Adapter) -> Result<(Device, Queue), RequestDeviceError> {
+    // The voxels of the world are in a single storage buffer, so large worlds need as large a
+    // buffer as the adapter allows.
+    let limits = Limits {
+        max_storage_buffer_binding_size: adapter.limits().max_storage_buffer_binding_size,
+        max_buffer_size: adapter.limits().max_buffer_size,
+        ..Default::default()
+    };
+    adapter
+        .request_device(
+            &DeviceDescriptor {
+                label: Some("device"),
+                features: Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES
+                    | Features::SPIRV_SHADER_PASSTHROUGH,
+                limits,
+            },
+            None,
+        )
+        .await
+}
+
+/// Creates a device without a surface, falling back to a software adapter if there is no
+/// hardware one. Returns `None` if neither can provide the features the renderer needs.
+pub fn request_headless_device() -> Option<(Device, Queue)> {
+    let instance = Instance::new(Backends::all());
+    [false, true]
+        .into_iter()
+        .find_map(|force_fallback_adapter| {
+            let adapter = block_on(instance.request_adapter(&RequestAdapterOptions {
+                power_preference: PowerPreference::HighPerformance,
+                compatible_surface: None,
+                force_fallback_adapter,
+            }))?;
+            block_on(request_device(&adapter)).ok()
+        })
+}
+
 pub fn update_surface(
     window_size: Res<WindowSize>,
     mut config: ResMut<SurfaceConfigResource>,
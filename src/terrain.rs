@@ -0,0 +1,255 @@
+//! Filling a [`World`] from noise. W is up, so a heightmap is a function of X, Y and Z.
+
+use crate::noise::{Fbm, Noise, Perlin, Simplex, Worley};
+use crate::shapes::voxel_center;
+use crate::voxel::{VoxelId, VoxelType};
+use crate::world::World;
+use nalgebra::Vector4;
+use ndarray::Array4;
+use palette::Srgb;
+
+/// The kinds of terrain that can be generated from a seed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Generator {
+    Hills,
+    Caves,
+    Crystals,
+}
+
+impl Generator {
+    pub fn name(self) -> &'static str {
+        match self {
+            Generator::Hills => "hills",
+            Generator::Caves => "caves",
+            Generator::Crystals => "crystals",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Generator> {
+        [Generator::Hills, Generator::Caves, Generator::Crystals]
+            .into_iter()
+            .find(|x| x.name() == name)
+    }
+
+    /// Fills the world with the terrain. The same seed always gives the same terrain.
+    pub fn generate(self, world: &mut World, seed: u32) {
+        match self {
+            Generator::Hills => generate_hills(world, seed),
+            Generator::Caves => generate_caves(world, seed),
+            Generator::Crystals => generate_crystals(world, seed),
+        }
+    }
+}
+
+/// The values below `max` that are not in an earlier band.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Band {
+    pub max: f32,
+    pub id: VoxelId,
+}
+
+/// Voxel types by value, with bands ordered by `max`. Values past the last band are air.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Bands(pub Vec<Band>);
+
+impl Bands {
+    pub fn get(&self, value: f32) -> VoxelId {
+        self.0
+            .iter()
+            .find(|band| value < band.max)
+            .map_or_else(World::air, |band| band.id)
+    }
+}
+
+/// How many voxels apart [`fill_density`] samples the noise along each axis. The noise is
+/// interpolated in between, since at the frequencies used for terrain it barely changes from
+/// one voxel to the next, and sampling every voxel of a 4D world takes minutes.
+const DENSITY_STEP: u32 = 4;
+
+/// Sets every voxel of the world to the band of the density at its center. `frequency` is
+/// the number of noise lattice cells per voxel.
+pub fn fill_density(world: &mut World, noise: &impl Noise, frequency: f32, bands: &Bands) {
+    // One sample past the last voxel center along each axis, to interpolate towards.
+    let samples = (world.size() / DENSITY_STEP + 2) as usize;
+    let scale = DENSITY_STEP as f32 * frequency;
+    let density = Array4::from_shape_fn((samples, samples, samples, samples), |(x, y, z, w)| {
+        noise.sample(Vector4::new(x, y, z, w).cast::<f32>() * scale)
+    });
+    let voxels: Vec<_> = world
+        .positions()
+        .map(|position| {
+            let point = voxel_center(position) / DENSITY_STEP as f32;
+            bands.get(interpolate(&density, point))
+        })
+        .collect();
+    world.write_region(world.bounds(), voxels);
+}
+
+/// Linearly interpolates between the 16 samples around a point, in units of samples.
+fn interpolate(samples: &Array4<f32>, point: Vector4<f32>) -> f32 {
+    let cell = point.map(|x| x.floor() as usize);
+    let local = point - cell.cast();
+    let mut value = 0.0;
+    for corner in 0..16 {
+        let offset = Vector4::new(
+            corner & 1,
+            corner >> 1 & 1,
+            corner >> 2 & 1,
+            corner >> 3 & 1,
+        );
+        let weight: f32 = (0..4)
+            .map(|i| {
+                if offset[i] == 1 {
+                    local[i]
+                } else {
+                    1.0 - local[i]
+                }
+            })
+            .product();
+        let index = cell + offset;
+        value += weight * samples[[index.x, index.y, index.z, index.w]];
+    }
+    value
+}
+
+/// A surface at `base` plus `amplitude` times the noise.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Heightmap<N> {
+    pub noise: N,
+    /// The number of noise lattice cells per voxel.
+    pub frequency: f32,
+    pub base: f32,
+    pub amplitude: f32,
+}
+
+impl<N: Noise> Heightmap<N> {
+    pub fn height(&self, x: f32, y: f32, z: f32) -> f32 {
+        let point = Vector4::new(x, y, z, 0.0) * self.frequency;
+        self.base + self.amplitude * self.noise.sample(point)
+    }
+}
+
+/// Sets every voxel of the world to the band of its depth below the heightmap, or to air
+/// above it.
+pub fn fill_heightmap<N: Noise>(world: &mut World, heightmap: &Heightmap<N>, bands: &Bands) {
+    let mut height = 0.0;
+    let voxels: Vec<_> = world
+        .positions()
+        .map(|position| {
+            let center = voxel_center(position);
+            // Positions go up a column with W varying fastest.
+            if position.w == 0 {
+                height = heightmap.height(center.x, center.y, center.z);
+            }
+            let depth = height - center.w;
+            if depth < 0.0 {
+                World::air()
+            } else {
+                bands.get(depth)
+            }
+        })
+        .collect();
+    world.write_region(world.bounds(), voxels);
+}
+
+/// Rolling hills of grass over dirt over stone.
+pub fn generate_hills(world: &mut World, seed: u32) {
+    let grass = world.insert_type(VoxelType::new(
+        Srgb::new(0.286, 0.435, 0.220),
+        1.0,
+        0.0,
+        1.0,
+    ));
+    let dirt = world.insert_type(VoxelType::new(
+        Srgb::new(0.400, 0.298, 0.200),
+        1.0,
+        0.0,
+        1.0,
+    ));
+    let stone = world.insert_type(VoxelType::new(
+        Srgb::new(0.439, 0.447, 0.463),
+        1.0,
+        0.0,
+        0.6,
+    ));
+    let size = world.size() as f32;
+    let heightmap = Heightmap {
+        noise: Fbm::new(Simplex { seed }, 4),
+        frequency: 1.0 / 32.0,
+        base: size * 0.35,
+        amplitude: size * 0.25,
+    };
+    let bands = Bands(vec![
+        Band {
+            max: 1.0,
+            id: grass,
+        },
+        Band { max: 4.0, id: dirt },
+        Band {
+            max: f32::INFINITY,
+            id: stone,
+        },
+    ]);
+    fill_heightmap(world, &heightmap, &bands);
+}
+
+/// Solid stone riddled with winding caves.
+pub fn generate_caves(world: &mut World, seed: u32) {
+    let stone = world.insert_type(VoxelType::new(
+        Srgb::new(0.439, 0.447, 0.463),
+        1.0,
+        0.0,
+        0.6,
+    ));
+    let ore = world.insert_type(VoxelType::new(
+        Srgb::new(0.722, 0.451, 0.200),
+        1.0,
+        0.3,
+        0.6,
+    ));
+    let bands = Bands(vec![
+        Band { max: -0.2, id: ore },
+        Band {
+            max: 0.15,
+            id: stone,
+        },
+    ]);
+    fill_density(world, &Fbm::new(Perlin { seed }, 2), 1.0 / 16.0, &bands);
+}
+
+/// Translucent crystals around scattered points, one in every noise lattice cell.
+pub fn generate_crystals(world: &mut World, seed: u32) {
+    let crystal = world.insert_type(VoxelType::new(
+        Srgb::new(0.580, 0.396, 0.839),
+        0.5,
+        0.4,
+        0.2,
+    ));
+    let bands = Bands(vec![Band {
+        max: 0.45,
+        id: crystal,
+    }]);
+    fill_density(world, &Worley { seed }, 1.0 / 12.0, &bands);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generate(generator: Generator, seed: u32) -> Vec<u8> {
+        let mut world = World::new(16);
+        generator.generate(&mut world, seed);
+        let mut bytes = Vec::new();
+        world.write_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn generators_are_deterministic() {
+        for generator in [Generator::Hills, Generator::Caves, Generator::Crystals] {
+            let world = generate(generator, 7);
+            assert!(world == generate(generator, 7), "{:?}", generator);
+            assert!(world != generate(generator, 8), "{:?}", generator);
+        }
+    }
+}
@@ -1,4 +1,8 @@
+use crate::background::BackgroundInternal;
 use crate::camera_3d::CameraInternal;
+use crate::depth_palette::DepthPaletteInternal;
+use crate::fog::FogInternal;
+use crate::light::LightsInternal;
 use crate::surface::{DeviceResource, QueueResource};
 use crate::voxel::VoxelTypeInternal;
 use crate::window_size::WindowSize;
@@ -13,9 +17,36 @@ pub struct Uniforms {
     pub camera: CameraInternal,
     pub window_size: Vector2<f32>,
     _padding: [f32; 2],
+    pub fog: FogInternal,
+    pub depth_palette: DepthPaletteInternal,
+    pub lights: LightsInternal,
+    pub background: BackgroundInternal,
     pub voxel_types: [VoxelTypeInternal; 256],
 }
 
+impl Uniforms {
+    pub fn new(
+        camera: CameraInternal,
+        window_size: Vector2<f32>,
+        fog: FogInternal,
+        depth_palette: DepthPaletteInternal,
+        lights: LightsInternal,
+        background: BackgroundInternal,
+        voxel_types: [VoxelTypeInternal; 256],
+    ) -> Self {
+        Uniforms {
+            camera,
+            window_size,
+            _padding: Default::default(),
+            fog,
+            depth_palette,
+            lights,
+            background,
+            voxel_types,
+        }
+    }
+}
+
 #[derive(Resource)]
 pub struct UniformBuffer(pub Buffer);
 #[derive(Resource)]
@@ -53,12 +84,15 @@ pub fn init_uniforms(
             resource: buffer.as_entire_binding(),
         }],
     });
-    commands.insert_resource(Uniforms {
-        camera: Default::default(),
-        window_size: window_size.0.cast(),
-        _padding: Default::default(),
-        voxel_types: [Default::default(); 256],
-    });
+    commands.insert_resource(Uniforms::new(
+        Default::default(),
+        window_size.0.cast(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        [Default::default(); 256],
+    ));
     commands.insert_resource(UniformBuffer(buffer));
     commands.insert_resource(UniformBindGroup(bind_group, bind_group_layout));
 }
@@ -1,5 +1,6 @@
 use crate::camera_4d::CameraInternal;
 use crate::surface::{DeviceResource, QueueResource};
+use crate::voxel::VoxelTypeInternal;
 use crate::world::WorldSize;
 use bevy::prelude::*;
 use bytemuck::{Pod, Zeroable};
@@ -10,6 +11,8 @@ use wgpu::*;
 pub struct Uniforms {
     pub camera: CameraInternal,
     world_size: u32,
+    _padding: [u32; 3],
+    pub voxel_types: [VoxelTypeInternal; 256],
 }
 
 #[derive(Resource)]
@@ -52,6 +55,8 @@ pub fn init_uniforms(
     commands.insert_resource(Uniforms {
         camera: Default::default(),
         world_size: world_size.0 + 2,
+        _padding: Default::default(),
+        voxel_types: [Default::default(); 256],
     });
     commands.insert_resource(UniformBuffer(buffer));
     commands.insert_resource(UniformBindGroup(bind_group, bind_group_layout));
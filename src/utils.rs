@@ -1,7 +1,107 @@
 use byteorder::{ByteOrder, LittleEndian};
+use ndarray::{Array3, Axis};
+use png::{BitDepth, ColorType, Encoder};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::mpsc;
+use wgpu::{BufferSlice, Device, Maintain, MapMode};
 
 pub fn to_u32_array(x: &[u8]) -> Vec<u32> {
     let mut out = vec![0; x.len() / 4];
     LittleEndian::read_u32_into(x, &mut out);
     out
 }
+
+/// GLSL's `sign`, which unlike [`f32::signum`] returns zero for zero.
+pub fn sign(x: f32) -> f32 {
+    if x > 0.0 {
+        1.0
+    } else if x < 0.0 {
+        -1.0
+    } else {
+        0.0
+    }
+}
+
+/// Writes pixels indexed by `(y, x, channel)` as a binary PPM, dropping the alpha channel.
+pub fn write_ppm(path: impl AsRef<Path>, pixels: &Array3<u8>) -> io::Result<()> {
+    let (height, width, _) = pixels.dim();
+    let mut file = BufWriter::new(File::create(path)?);
+    write!(file, "P6\n{} {}\n255\n", width, height)?;
+    for pixel in pixels.lanes(Axis(2)) {
+        file.write_all(&[pixel[0], pixel[1], pixel[2]])?;
+    }
+    file.flush()
+}
+
+/// Writes sRGB pixels indexed by `(y, x, channel)` as a PNG, dropping the alpha channel.
+/// Viewers assume untagged PNGs are sRGB, so no color space chunk is written.
+pub fn write_png(path: impl AsRef<Path>, pixels: &Array3<u8>) -> io::Result<()> {
+    let (height, width, _) = pixels.dim();
+    let mut encoder = Encoder::new(
+        BufWriter::new(File::create(path)?),
+        width as u32,
+        height as u32,
+    );
+    encoder.set_color(ColorType::Rgb);
+    encoder.set_depth(BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    let data: Vec<u8> = pixels
+        .lanes(Axis(2))
+        .into_iter()
+        .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]])
+        .collect();
+    // The end of the image is written when the writer is dropped.
+    writer.write_image_data(&data)?;
+    Ok(())
+}
+
+/// Reads a binary PPM written by [`write_ppm`], with an opaque alpha channel added back.
+#[cfg(test)]
+pub fn read_ppm(path: impl AsRef<Path>) -> io::Result<Array3<u8>> {
+    use std::io::{BufRead, BufReader, Read};
+
+    let mut file = BufReader::new(File::open(path)?);
+    // The magic number, the size and the maximum value, each on their own line.
+    let mut header = Vec::new();
+    for _ in 0..3 {
+        let mut line = String::new();
+        file.read_line(&mut line)?;
+        header.push(line.trim().to_string());
+    }
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Not a binary PPM");
+    if header[0] != "P6" || header[2] != "255" {
+        return Err(invalid());
+    }
+    let size: Vec<usize> = header[1]
+        .split(' ')
+        .map(|x| x.parse().map_err(|_| invalid()))
+        .collect::<io::Result<_>>()?;
+    let (width, height) = match size[..] {
+        [width, height] => (width, height),
+        _ => return Err(invalid()),
+    };
+    let mut rgb = vec![0; width * height * 3];
+    file.read_exact(&mut rgb)?;
+    let pixels = rgb
+        .chunks(3)
+        .flat_map(|pixel| [pixel[0], pixel[1], pixel[2], 255]);
+    Ok(Array3::from_shape_vec((height, width, 4), pixels.collect()).unwrap())
+}
+
+/// Maps a buffer slice for reading, blocking until it is mapped or mapping failed.
+pub fn map_read(device: &Device, slice: &BufferSlice) -> io::Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    slice.map_async(MapMode::Read, move |result| {
+        // Only fails if `map_read` already gave up on the result.
+        let _ = sender.send(result);
+    });
+    device.poll(Maintain::Wait);
+    // Waiting for the device runs the callback, so the result is there unless mapping never
+    // finished.
+    match receiver.try_recv() {
+        Ok(result) => result.map_err(io::Error::other),
+        Err(_) => Err(io::Error::other("The buffer was never mapped")),
+    }
+}
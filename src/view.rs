@@ -1,5 +1,10 @@
 use crate::surface::DeviceResource;
+use crate::utils::map_read;
+use crate::voxel::VoxelId;
 use bevy::prelude::*;
+use ndarray::Array3;
+use std::io;
+use std::num::NonZeroU32;
 use wgpu::*;
 
 #[derive(Resource, Copy, Clone, Debug, PartialEq, Eq)]
@@ -7,6 +12,69 @@ pub struct ViewSize(pub u32);
 
 #[derive(Resource)]
 pub struct ViewTexture(pub Texture, pub Extent3d);
+
+impl ViewTexture {
+    /// Copies the view voxels back from the GPU, blocking until the copy has finished. Indexed
+    /// by texture coordinates, like [`crate::render_4d::TracedView::voxels`].
+    pub fn read(&self, device: &Device, queue: &Queue) -> io::Result<Array3<VoxelId>> {
+        let size = self.1;
+        // Rows copied out of a texture have to be aligned to `COPY_BYTES_PER_ROW_ALIGNMENT`.
+        let align = COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_bytes_per_row = size.width.div_ceil(align) * align;
+        let buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("view-readback-buffer"),
+            size: (padded_bytes_per_row * size.height * size.depth_or_array_layers) as u64,
+            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+            label: Some("view-readback-encoder"),
+        });
+        encoder.copy_texture_to_buffer(
+            ImageCopyTexture {
+                texture: &self.0,
+                mip_level: 0,
+                origin: Origin3d::ZERO,
+                aspect: TextureAspect::All,
+            },
+            ImageCopyBuffer {
+                buffer: &buffer,
+                layout: ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(padded_bytes_per_row),
+                    rows_per_image: NonZeroU32::new(size.height),
+                },
+            },
+            size,
+        );
+        queue.submit(std::iter::once(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        map_read(device, &slice)?;
+
+        let width = size.width as usize;
+        let mut voxels =
+            Vec::with_capacity(width * (size.height * size.depth_or_array_layers) as usize);
+        for row in slice
+            .get_mapped_range()
+            .chunks(padded_bytes_per_row as usize)
+        {
+            voxels.extend(row[..width].iter().map(|&x| VoxelId(x)));
+        }
+        buffer.unmap();
+
+        // The texture is laid out with x varying fastest.
+        let shape = (
+            size.depth_or_array_layers as usize,
+            size.height as usize,
+            width,
+        );
+        Ok(Array3::from_shape_vec(shape, voxels)
+            .unwrap()
+            .reversed_axes())
+    }
+}
 #[derive(Resource)]
 pub struct ViewDepthTexture(pub Texture, pub Extent3d);
 #[derive(Resource)]
@@ -32,6 +100,7 @@ pub fn init_view(mut commands: Commands, size: Res<ViewSize>, device: Res<Device
         format: TextureFormat::R8Uint,
         usage: TextureUsages::STORAGE_BINDING
             | TextureUsages::COPY_DST
+            | TextureUsages::COPY_SRC
             | TextureUsages::TEXTURE_BINDING,
     });
     let view = texture.create_view(&TextureViewDescriptor::default());
@@ -48,11 +117,24 @@ pub fn init_view(mut commands: Commands, size: Res<ViewSize>, device: Res<Device
         mip_level_count: 1,
         sample_count: 1,
         dimension: TextureDimension::D3,
-        format: TextureFormat::R8Uint,
-        usage: TextureUsages::STORAGE_BINDING,
+        format: TextureFormat::R32Float,
+        usage: TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING,
     });
     let depth_view = depth_texture.create_view(&TextureViewDescriptor::default());
 
+    // Translucent voxels in front of the voxel in the view texture, blended into a
+    // premultiplied RGBA color. Only the bind groups use it, and they keep it alive.
+    let tint_texture = device.create_texture(&TextureDescriptor {
+        label: Some("view-tint-texture"),
+        size: extent,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D3,
+        format: TextureFormat::Rgba16Float,
+        usage: TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING,
+    });
+    let tint_view = tint_texture.create_view(&TextureViewDescriptor::default());
+
     let bind_group_layout_3d = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
         label: Some("view-3d-bind-group-layout"),
         entries: &[
@@ -72,6 +154,26 @@ pub fn init_view(mut commands: Commands, size: Res<ViewSize>, device: Res<Device
                 ty: BindingType::Sampler(SamplerBindingType::NonFiltering),
                 count: None,
             },
+            BindGroupLayoutEntry {
+                binding: 2,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: TextureViewDimension::D3,
+                    sample_type: TextureSampleType::Float { filterable: false },
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 3,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: TextureViewDimension::D3,
+                    sample_type: TextureSampleType::Float { filterable: false },
+                },
+                count: None,
+            },
         ],
     });
 
@@ -87,6 +189,14 @@ pub fn init_view(mut commands: Commands, size: Res<ViewSize>, device: Res<Device
                 binding: 1,
                 resource: BindingResource::Sampler(&sampler),
             },
+            BindGroupEntry {
+                binding: 2,
+                resource: BindingResource::TextureView(&depth_view),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: BindingResource::TextureView(&tint_view),
+            },
         ],
     });
 
@@ -108,7 +218,17 @@ pub fn init_view(mut commands: Commands, size: Res<ViewSize>, device: Res<Device
                 visibility: ShaderStages::COMPUTE,
                 ty: BindingType::StorageTexture {
                     access: StorageTextureAccess::WriteOnly,
-                    format: TextureFormat::R8Uint,
+                    format: TextureFormat::R32Float,
+                    view_dimension: TextureViewDimension::D3,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 2,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::StorageTexture {
+                    access: StorageTextureAccess::WriteOnly,
+                    format: TextureFormat::Rgba16Float,
                     view_dimension: TextureViewDimension::D3,
                 },
                 count: None,
@@ -128,6 +248,10 @@ pub fn init_view(mut commands: Commands, size: Res<ViewSize>, device: Res<Device
                 binding: 1,
                 resource: BindingResource::TextureView(&depth_view),
             },
+            BindGroupEntry {
+                binding: 2,
+                resource: BindingResource::TextureView(&tint_view),
+            },
         ],
     });
 
@@ -0,0 +1,475 @@
+//! Importing MagicaVoxel `.vox` models into a [`World`].
+//!
+//! A `.vox` file is the magic `VOX `, a version and a `MAIN` chunk holding the other chunks.
+//! Every chunk is a four byte id, the size of its content, the size of its children, the
+//! content and then the children. All integers are little endian. Only these chunks are read:
+//!
+//! | Chunk  | Content                                                                   |
+//! |--------|---------------------------------------------------------------------------|
+//! | `SIZE` | Size of the next model as three `i32`s                                    |
+//! | `XYZI` | `i32` number of voxels, then `x`, `y`, `z` and color index as `u8`s each  |
+//! | `RGBA` | 256 RGBA colors, where color index `i` is the color at `i - 1`            |
+//!
+//! The scene graph and material chunks are skipped, so models are imported in the order they
+//! are stored in.
+
+use crate::region::Region;
+use crate::voxel::{VoxelId, VoxelType};
+use crate::world::World;
+use byteorder::{LittleEndian, ReadBytesExt};
+use nalgebra::{Vector3, Vector4};
+use palette::Srgb;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"VOX ";
+
+/// Voxel coordinates are bytes, so models can't be larger than this along any axis.
+pub const MAX_MODEL_SIZE: u32 = 256;
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// A model of a `.vox` file, with Z up.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VoxModel {
+    pub size: Vector3<u32>,
+    /// The color index of every voxel that isn't empty. Only these are stored, so that a file
+    /// can't make the model take more memory than the file itself.
+    pub voxels: HashMap<Vector3<u8>, u8>,
+}
+
+impl VoxModel {
+    /// The color index at a position, or zero if it is empty or outside of the model.
+    fn get(&self, position: Vector3<i64>) -> u8 {
+        if position
+            .iter()
+            .any(|&x| !(0..MAX_MODEL_SIZE as i64).contains(&x))
+        {
+            return 0;
+        }
+        let position = position.map(|x| x as u8);
+        self.voxels.get(&position).copied().unwrap_or(0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VoxFile {
+    pub models: Vec<VoxModel>,
+    /// RGBA colors by color index. Index zero is unused.
+    pub palette: Vec<[u8; 4]>,
+}
+
+struct Chunk<'a> {
+    id: [u8; 4],
+    content: &'a [u8],
+    children: &'a [u8],
+}
+
+/// Reads a chunk from the start of `input`, checking that its sizes fit into what is left.
+fn read_chunk<'a>(input: &mut &'a [u8]) -> io::Result<Chunk<'a>> {
+    let mut id = [0; 4];
+    input.read_exact(&mut id)?;
+    let content_size = input.read_u32::<LittleEndian>()? as usize;
+    let children_size = input.read_u32::<LittleEndian>()? as usize;
+    if content_size > input.len() || children_size > input.len() - content_size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Chunk is larger than the file",
+        ));
+    }
+    let (content, rest) = input.split_at(content_size);
+    let (children, rest) = rest.split_at(children_size);
+    *input = rest;
+    Ok(Chunk {
+        id,
+        content,
+        children,
+    })
+}
+
+impl VoxFile {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<VoxFile> {
+        VoxFile::read_from(&mut BufReader::new(File::open(path)?))
+    }
+
+    pub fn read_from(reader: &mut impl Read) -> io::Result<VoxFile> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("Not a .vox file"));
+        }
+        let _version = reader.read_u32::<LittleEndian>()?;
+
+        // Read whole, so that chunk sizes can be checked against what is actually there.
+        let mut input = Vec::new();
+        reader.read_to_end(&mut input)?;
+        let main = read_chunk(&mut &input[..])?;
+        if &main.id != b"MAIN" {
+            return Err(invalid_data("Missing MAIN chunk"));
+        }
+
+        let mut models = Vec::new();
+        let mut palette = None;
+        let mut size = None;
+        let mut children = main.children;
+        while !children.is_empty() {
+            let chunk = read_chunk(&mut children)?;
+            let mut content = chunk.content;
+            match &chunk.id {
+                b"SIZE" => {
+                    let mut read = || content.read_u32::<LittleEndian>();
+                    let model_size = Vector3::new(read()?, read()?, read()?);
+                    if model_size.iter().any(|&x| x > MAX_MODEL_SIZE) {
+                        return Err(invalid_data("Model is too large"));
+                    }
+                    size = Some(model_size);
+                }
+                b"XYZI" => {
+                    let size = size
+                        .take()
+                        .ok_or_else(|| invalid_data("XYZI without SIZE"))?;
+                    let count = content.read_u32::<LittleEndian>()? as usize;
+                    if count > content.len() / 4 {
+                        return Err(invalid_data("XYZI is shorter than its voxel count"));
+                    }
+                    let mut voxels = HashMap::with_capacity(count);
+                    for voxel in content[..count * 4].chunks(4) {
+                        let position = Vector3::new(voxel[0], voxel[1], voxel[2]);
+                        if position
+                            .iter()
+                            .zip(size.iter())
+                            .any(|(&x, &size)| x as u32 >= size)
+                        {
+                            return Err(invalid_data("Voxel outside of its model"));
+                        }
+                        if voxel[3] != 0 {
+                            voxels.insert(position, voxel[3]);
+                        }
+                    }
+                    models.push(VoxModel { size, voxels });
+                }
+                b"RGBA" => {
+                    let mut colors = vec![[0; 4]; 256];
+                    for color in &mut colors[1..] {
+                        content.read_exact(color)?;
+                    }
+                    palette = Some(colors);
+                }
+                _ => {}
+            }
+        }
+
+        // Files written by MagicaVoxel always have a palette. Older files may rely on its
+        // default palette, which isn't included here.
+        let palette = palette.ok_or_else(|| invalid_data("Missing RGBA chunk"))?;
+        if models.is_empty() {
+            return Err(invalid_data("No models"));
+        }
+        Ok(VoxFile { models, palette })
+    }
+
+    /// Adds the colors used by the models to the world as voxel types, returning the voxel id
+    /// for every color index. Colors equal to a type the world already has, for example from
+    /// an earlier import, reuse it. Inserts nothing if the new types don't all fit.
+    fn insert_types(&self, world: &mut World) -> io::Result<[VoxelId; 256]> {
+        let mut used = [false; 256];
+        for model in &self.models {
+            for &color in model.voxels.values() {
+                used[color as usize] = true;
+            }
+        }
+        let mut types: Vec<(usize, VoxelType)> = Vec::new();
+        for color in (1..256).filter(|&x| used[x]) {
+            let [red, green, blue, alpha] = self.palette[color];
+            let ty = VoxelType::new(
+                Srgb::new(red, green, blue).into_format(),
+                alpha as f32 / 255.0,
+                0.0,
+                1.0,
+            );
+            types.push((color, ty));
+        }
+        let mut missing: Vec<VoxelType> = Vec::new();
+        for (_, ty) in &types {
+            if world.find_type(ty).is_none() && !missing.contains(ty) {
+                missing.push(*ty);
+            }
+        }
+        if world.types().len() + missing.len() > 256 {
+            return Err(invalid_data(
+                "The world has no room for the colors of the model",
+            ));
+        }
+
+        let mut ids = [World::air(); 256];
+        for (color, ty) in types {
+            ids[color] = match world.find_type(&ty) {
+                Some(id) => id,
+                None => world
+                    .try_insert_type(ty)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+            };
+        }
+        Ok(ids)
+    }
+}
+
+/// How the 3D models of a `.vox` file become 4D.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Import {
+    /// The first model, repeated `length` times along the remaining axis.
+    Extrude { length: u32 },
+    /// The first model spun around the plane of its Y and Z axes, through the full turn in the
+    /// plane of its X axis and the remaining axis. The model's X is the distance from the
+    /// plane, so the plane lies at the origin of the placement.
+    Revolve,
+    /// Each model as one layer along the remaining axis, in the order they are stored in.
+    Stack,
+}
+
+impl Import {
+    /// Parses `extrude:<length>`, `revolve` or `stack`.
+    pub fn parse(mode: &str) -> Option<Import> {
+        match mode {
+            "revolve" => Some(Import::Revolve),
+            "stack" => Some(Import::Stack),
+            _ => {
+                let length = mode.strip_prefix("extrude:")?.parse().ok()?;
+                Some(Import::Extrude { length })
+            }
+        }
+    }
+}
+
+/// Where models are imported to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Placement {
+    /// The world position of the model's origin. May lie outside of the world, in which case
+    /// the model is clipped.
+    pub origin: Vector4<i64>,
+    /// The world axis each of the model's X, Y and Z axes go along. The axis not listed is the
+    /// remaining axis.
+    pub axes: [usize; 3],
+}
+
+impl Default for Placement {
+    /// At the origin, with the model's Z up along W and extruded along Z.
+    fn default() -> Self {
+        Placement {
+            origin: Vector4::zeros(),
+            axes: [0, 1, 3],
+        }
+    }
+}
+
+impl Placement {
+    /// The axis the model's axes don't go along. Fails unless they go along three different
+    /// world axes.
+    fn remaining_axis(&self) -> io::Result<usize> {
+        let [a, b, c] = self.axes;
+        if self.axes.iter().any(|&x| x >= 4) || a == b || b == c || a == c {
+            return Err(invalid_input(
+                "Model axes must be three different world axes",
+            ));
+        }
+        Ok((0..4).find(|x| !self.axes.contains(x)).unwrap())
+    }
+}
+
+/// Places the models into the world, clipped to its bounds. Empty model voxels leave the world
+/// as it is. Fails without changing the world if the placement is invalid or the world has
+/// no room for the colors of the models.
+pub fn import(
+    world: &mut World,
+    vox: &VoxFile,
+    placement: &Placement,
+    mode: Import,
+) -> io::Result<()> {
+    let d = placement.remaining_axis()?;
+    let ids = vox.insert_types(world)?;
+    let [a, b, c] = placement.axes;
+    let first = &vox.models[0];
+
+    // The extent of the import relative to the origin, along every world axis.
+    let mut min = Vector4::<i64>::zeros();
+    let mut max = Vector4::<i64>::zeros();
+    let size = first.size.cast::<i64>();
+    for (i, &axis) in placement.axes.iter().enumerate() {
+        max[axis] = match mode {
+            Import::Stack => vox.models.iter().map(|x| x.size[i]).max().unwrap() as i64,
+            _ => size[i],
+        };
+    }
+    match mode {
+        Import::Extrude { length } => max[d] = length as i64,
+        Import::Stack => max[d] = vox.models.len() as i64,
+        Import::Revolve => {
+            min[a] = -size.x;
+            min[d] = -size.x;
+            max[d] = size.x;
+        }
+    }
+
+    let world_size = world.size() as i64;
+    let clip = |x: Vector4<i64>| x.map(|x| x.clamp(0, world_size) as u32);
+    let region = Region::new(clip(placement.origin + min), clip(placement.origin + max));
+
+    let voxels: Vec<_> = region
+        .positions()
+        .map(|position| {
+            let p = position.cast::<i64>() - placement.origin;
+            let color = match mode {
+                Import::Extrude { .. } => first.get(Vector3::new(p[a], p[b], p[c])),
+                Import::Stack => vox.models[p[d] as usize].get(Vector3::new(p[a], p[b], p[c])),
+                Import::Revolve => {
+                    let (u, v) = (p[a] as f32 + 0.5, p[d] as f32 + 0.5);
+                    let radius = (u * u + v * v).sqrt() as i64;
+                    first.get(Vector3::new(radius, p[b], p[c]))
+                }
+            };
+            match color {
+                0 => world[position],
+                color => ids[color as usize],
+            }
+        })
+        .collect();
+    world.write_region(region, voxels);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxel::test_types;
+
+    /// Two models. The first is 2×3×4 with five voxels of colors 1 and 2, where three columns
+    /// along X have voxels: two of them at X 0 and 1 and one only at X 0. The second is a
+    /// single voxel of color 4, which is the same color as 1.
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/vox/two_models.vox");
+
+    fn placement() -> Placement {
+        Placement {
+            origin: Vector4::repeat(4),
+            ..Default::default()
+        }
+    }
+
+    fn filled(world: &World) -> usize {
+        world
+            .positions()
+            .filter(|&x| world[x] != World::air())
+            .count()
+    }
+
+    fn import_fixture(mode: Import) -> World {
+        let mut world = World::new(16);
+        let vox = VoxFile::load(FIXTURE).unwrap();
+        import(&mut world, &vox, &placement(), mode).unwrap();
+        world
+    }
+
+    #[test]
+    fn reads_models() {
+        let vox = VoxFile::load(FIXTURE).unwrap();
+        assert_eq!(vox.models.len(), 2);
+        assert_eq!(vox.models[0].size, Vector3::new(2, 3, 4));
+        assert_eq!(vox.models[0].voxels.len(), 5);
+        assert_eq!(vox.models[0].get(Vector3::new(0, 2, 3)), 2);
+        assert_eq!(vox.models[1].size, Vector3::new(1, 1, 1));
+        assert_eq!(vox.palette[1], vox.palette[4]);
+    }
+
+    #[test]
+    fn extrude() {
+        let world = import_fixture(Import::Extrude { length: 3 });
+        assert_eq!(filled(&world), 5 * 3);
+        // The remaining axis is Z.
+        assert_ne!(world[Vector4::new(4, 6, 6, 7)], World::air());
+        assert_eq!(world[Vector4::new(4, 6, 7, 7)], World::air());
+    }
+
+    #[test]
+    fn revolve() {
+        // A column with voxels at X 0 and 1 becomes rings of 4 and 8 voxels, and one with only
+        // X 0 the inner ring.
+        let world = import_fixture(Import::Revolve);
+        assert_eq!(filled(&world), 12 + 4 + 12);
+    }
+
+    #[test]
+    fn stack() {
+        let world = import_fixture(Import::Stack);
+        assert_eq!(filled(&world), 5 + 1);
+        assert_eq!(
+            world[Vector4::new(4, 4, 5, 4)],
+            world[Vector4::new(4, 4, 4, 4)]
+        );
+    }
+
+    #[test]
+    fn equal_colors_share_a_type() {
+        let mut world = import_fixture(Import::Stack);
+        assert_eq!(world.types().len(), 4);
+        let vox = VoxFile::load(FIXTURE).unwrap();
+        let placement = Placement {
+            origin: Vector4::repeat(10),
+            ..Default::default()
+        };
+        import(&mut world, &vox, &placement, Import::Stack).unwrap();
+        assert_eq!(world.types().len(), 4);
+        assert_eq!(filled(&world), 12);
+    }
+
+    #[test]
+    fn invalid_placements_are_errors() {
+        let vox = VoxFile::load(FIXTURE).unwrap();
+        for axes in [[0, 0, 1], [0, 1, 4]] {
+            let mut world = World::new(16);
+            let placement = Placement {
+                origin: Vector4::zeros(),
+                axes,
+            };
+            let err = import(&mut world, &vox, &placement, Import::Stack).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            assert_eq!(world.types().len(), 2);
+        }
+    }
+
+    #[test]
+    fn running_out_of_types_is_an_error() {
+        let mut world = World::new(16);
+        for i in 2..255 {
+            world.insert_type(test_types::filler(i));
+        }
+        let vox = VoxFile::load(FIXTURE).unwrap();
+        assert!(import(&mut world, &vox, &placement(), Import::Stack).is_err());
+        assert_eq!(world.types().len(), 255);
+        assert_eq!(filled(&world), 0);
+    }
+
+    #[test]
+    fn truncated_file_is_an_error() {
+        let bytes = std::fs::read(FIXTURE).unwrap();
+        for length in 0..bytes.len() {
+            assert!(VoxFile::read_from(&mut &bytes[..length]).is_err());
+        }
+    }
+
+    #[test]
+    fn oversized_models_are_errors() {
+        let mut bytes = std::fs::read(FIXTURE).unwrap();
+        // The X size of the first model, after the header, MAIN and the SIZE chunk header.
+        let offset = 8 + 12 + 12;
+        bytes[offset..offset + 4].copy_from_slice(&(MAX_MODEL_SIZE + 1).to_le_bytes());
+        let err = VoxFile::read_from(&mut &bytes[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
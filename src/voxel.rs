@@ -2,33 +2,62 @@ use bytemuck::{Pod, Zeroable};
 use derive_new::new;
 use palette::{LinSrgb, Srgb};
 
-#[derive(new, Copy, Clone, Debug, PartialEq, Default)]
+#[derive(new, Copy, Clone, Debug, PartialEq)]
 pub struct VoxelType {
     pub color: Srgb,
+    /// How much of what lies behind the voxel it hides, from 0 for fully transparent to 1 for
+    /// opaque.
+    pub opacity: f32,
+    /// How much light the voxel gives off in its own color, on top of the light it reflects.
+    pub emissive: f32,
+    /// From 0 for a sharp highlight to 1 for a fully matte surface.
+    pub roughness: f32,
 }
 
 impl VoxelType {
+    /// The type of air and solid air, which rays pass through.
+    pub fn air() -> Self {
+        VoxelType::new(Srgb::new(0.0, 0.0, 0.0), 0.0, 0.0, 1.0)
+    }
+
     pub fn to_internal(self) -> VoxelTypeInternal {
         VoxelTypeInternal {
             color: self.color.into_linear(),
-            _padding: 0.0,
+            opacity: self.opacity,
+            emissive: self.emissive,
+            roughness: self.roughness,
+            _padding: [0.0; 2],
         }
     }
 }
 
+/// An opaque, fully rough type that gives off no light, like the types of world files from
+/// before opacity and materials were stored.
+impl Default for VoxelType {
+    fn default() -> Self {
+        VoxelType::new(Srgb::new(0.0, 0.0, 0.0), 1.0, 0.0, 1.0)
+    }
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
 pub struct VoxelTypeInternal {
-    color: LinSrgb,
-    _padding: f32,
+    pub color: LinSrgb,
+    pub opacity: f32,
+    pub emissive: f32,
+    pub roughness: f32,
+    _padding: [f32; 2],
+}
+
+impl VoxelTypeInternal {
+    pub fn is_opaque(&self) -> bool {
+        self.opacity >= 1.0
+    }
 }
 
 impl Default for VoxelTypeInternal {
     fn default() -> Self {
-        VoxelTypeInternal {
-            color: LinSrgb::new(0.0, 0.0, 0.0),
-            _padding: 0.0,
-        }
+        VoxelType::default().to_internal()
     }
 }
 
@@ -38,3 +67,24 @@ unsafe impl Pod for VoxelTypeInternal {}
 #[repr(transparent)]
 #[derive(Pod, Zeroable, Copy, Clone, Debug, PartialEq, Eq)]
 pub struct VoxelId(pub u8);
+
+/// Voxel types for the tests of code that edits worlds.
+#[cfg(test)]
+pub mod test_types {
+    use super::VoxelType;
+    use palette::Srgb;
+
+    pub fn stone() -> VoxelType {
+        VoxelType::new(Srgb::new(0.5, 0.5, 0.5), 1.0, 0.0, 0.8)
+    }
+
+    pub fn glass() -> VoxelType {
+        VoxelType::new(Srgb::new(0.2, 0.4, 0.9), 0.3, 0.1, 0.0)
+    }
+
+    /// An opaque type with a different shade of red for each `i`, for using up the ids of a
+    /// world.
+    pub fn filler(i: u8) -> VoxelType {
+        VoxelType::new(Srgb::new(i as f32 / 255.0, 0.0, 0.0), 1.0, 0.0, 1.0)
+    }
+}
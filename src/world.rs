@@ -1,56 +1,131 @@
+use crate::region::Region;
 use crate::surface::{DeviceResource, QueueResource};
 use crate::voxel::{VoxelId, VoxelTypeInternal};
-use crate::{uniform_3d, VoxelType};
+use crate::{uniform_3d, uniform_4d, VoxelType};
 use arrayvec::ArrayVec;
 use bevy::prelude::*;
 use nalgebra::Vector4;
-use ndarray::{s, Array4};
-use std::num::NonZeroU32;
-use std::ops::{Index, IndexMut};
+use ndarray::Array4;
+use std::cmp::Ordering;
+use std::error::Error;
+use std::fmt;
+use std::ops::Index;
 use wgpu::*;
 
 #[derive(Resource, Copy, Clone, Debug, PartialEq, Eq)]
 pub struct WorldSize(pub u32);
 
+/// Side length of the 4D chunks the world is stored in.
+pub const CHUNK_SIZE: u32 = 8;
+/// Number of voxels in a chunk, which is also its size in bytes.
+pub const CHUNK_VOLUME: usize = (CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE) as usize;
+
+/// Once more dirty regions than this accumulate they are merged into one.
+const MAX_DIRTY_REGIONS: usize = 16;
+
+static AIR: VoxelId = VoxelId(0);
+
+/// The voxels of the world, stored as `CHUNK_SIZE`⁴ chunks that are only allocated once
+/// something is written to them.
 #[derive(Resource, Debug, Clone)]
 pub struct World {
-    voxels: Array4<VoxelId>,
+    size: u32,
+    /// For every chunk position, the index of the chunk in `voxels` plus one, or zero if the
+    /// chunk is empty.
+    chunk_index: Array4<u32>,
+    /// The allocated chunks, each `CHUNK_VOLUME` voxels long with `w` varying fastest.
+    voxels: Vec<VoxelId>,
     types: ArrayVec<VoxelType, 256>,
     types_internal: ArrayVec<VoxelTypeInternal, 256>,
+    /// Regions written to since the last upload to the GPU.
+    dirty: Vec<Region>,
 }
 
 impl World {
     pub fn new(size: u32) -> World {
-        let size = size as usize + 2;
-
         let mut types = ArrayVec::new();
         let mut types_internal = ArrayVec::new();
-        types.push(VoxelType::default());
-        types.push(VoxelType::default());
+        types.push(VoxelType::air());
+        types.push(VoxelType::air());
         types_internal.push(types[0].to_internal());
         types_internal.push(types[1].to_internal());
 
-        let mut voxels = Array4::from_elem((size, size, size, size), VoxelId(1));
-        voxels
-            .slice_mut(s![
-                1..(size - 1),
-                1..(size - 1),
-                1..(size - 1),
-                1..(size - 1)
-            ])
-            .fill(VoxelId(0));
+        let chunks = Self::chunks_per_axis(size) as usize;
         World {
-            voxels,
+            size,
+            chunk_index: Array4::zeros((chunks, chunks, chunks, chunks)),
+            voxels: Vec::new(),
             types,
             types_internal,
+            dirty: Vec::new(),
         }
     }
 
+    fn chunks_per_axis(size: u32) -> u32 {
+        size.div_ceil(CHUNK_SIZE)
+    }
+
+    /// Adds a type at the next free id. Panics if all 256 ids are taken.
     pub fn insert_type(&mut self, ty: VoxelType) -> VoxelId {
+        self.try_insert_type(ty).expect("Too many voxel types")
+    }
+
+    /// Adds a type at the next free id.
+    pub fn try_insert_type(&mut self, ty: VoxelType) -> Result<VoxelId, TooManyTypes> {
+        if self.types.is_full() {
+            return Err(TooManyTypes);
+        }
         let id = self.types.len();
         self.types.push(ty);
         self.types_internal.push(ty.to_internal());
-        VoxelId(id as u8)
+        Ok(VoxelId(id as u8))
+    }
+
+    /// The first type after air and solid air that is equal to `ty`.
+    pub fn find_type(&self, ty: &VoxelType) -> Option<VoxelId> {
+        let index = self.types.iter().skip(2).position(|x| x == ty)?;
+        Some(VoxelId(index as u8 + 2))
+    }
+
+    /// Adds a type at `id`, moving the types from `id` on, and the voxels using them, up by
+    /// one. Panics if all 256 ids are taken or `id` would leave a gap.
+    pub fn insert_type_at(&mut self, id: VoxelId, ty: VoxelType) {
+        let index = id.0 as usize;
+        assert!(index >= 2 && index <= self.types.len(), "Invalid type id");
+        self.types.insert(index, ty);
+        self.types_internal.insert(index, ty.to_internal());
+        self.remap_voxels(|x| if x.0 >= id.0 { VoxelId(x.0 + 1) } else { x });
+    }
+
+    /// Removes the type at `id`, moving the types after it, and the voxels using them, down by
+    /// one. Voxels still using the removed type become air. Air and solid air are never
+    /// removed.
+    pub fn remove_type(&mut self, id: VoxelId) -> Option<VoxelType> {
+        let index = id.0 as usize;
+        if index < 2 || index >= self.types.len() {
+            return None;
+        }
+        self.types_internal.remove(index);
+        let ty = self.types.remove(index);
+        self.remap_voxels(|x| match x.0.cmp(&id.0) {
+            Ordering::Less => x,
+            Ordering::Equal => Self::air(),
+            Ordering::Greater => VoxelId(x.0 - 1),
+        });
+        Some(ty)
+    }
+
+    /// Changes the id of every voxel, marking the whole world dirty if any of them changed.
+    fn remap_voxels(&mut self, f: impl Fn(VoxelId) -> VoxelId) {
+        let mut changed = false;
+        for voxel in &mut self.voxels {
+            let id = f(*voxel);
+            changed |= id != *voxel;
+            *voxel = id;
+        }
+        if changed {
+            self.mark_dirty(self.bounds());
+        }
     }
 
     pub fn air() -> VoxelId {
@@ -62,146 +137,457 @@ impl World {
     }
 
     pub fn size(&self) -> u32 {
-        self.voxels.shape()[0] as u32 - 2
+        self.size
+    }
+
+    pub fn types(&self) -> &[VoxelType] {
+        &self.types
+    }
+
+    /// Number of chunks that have been allocated.
+    pub fn chunk_count(&self) -> usize {
+        self.voxels.len() / CHUNK_VOLUME
+    }
+
+    pub fn bounds(&self) -> Region {
+        Region::cube(self.size)
+    }
+
+    /// Every position inside the world, with `w` varying fastest.
+    pub fn positions(&self) -> impl Iterator<Item = Vector4<u32>> {
+        positions(self.size())
+    }
+
+    /// Sets a single voxel. Writing the id a voxel already has changes nothing, so writing air
+    /// where no chunk is allocated neither allocates one nor needs an upload.
+    pub fn set(&mut self, index: Vector4<u32>, id: VoxelId) {
+        if self[index] != id {
+            *self.voxel_mut(index) = id;
+            self.mark_dirty(Region::point(index));
+        }
     }
 
-    fn texture_layout(&self) -> ImageDataLayout {
-        let size = self.size() + 2;
-        ImageDataLayout {
-            offset: 0,
-            bytes_per_row: NonZeroU32::new(size),
-            rows_per_image: NonZeroU32::new(size),
+    /// Sets the voxels in the region to `voxels`, given in the order of [`Region::positions`].
+    /// Voxels that already have the right id are not written, so chunks are only allocated
+    /// where something changes.
+    pub fn write_region(&mut self, region: Region, voxels: impl IntoIterator<Item = VoxelId>) {
+        for (position, id) in region.positions().zip(voxels) {
+            if self[position] != id {
+                *self.voxel_mut(position) = id;
+            }
+        }
+        self.mark_dirty(region);
+    }
+
+    /// Regions written to since they were last taken, which is when they are uploaded.
+    pub fn take_dirty(&mut self) -> Vec<Region> {
+        std::mem::take(&mut self.dirty)
+    }
+
+    /// Records that a region needs to be uploaded, merging it into a region it touches so
+    /// that runs of edits don't accumulate one region per voxel.
+    pub fn mark_dirty(&mut self, region: Region) {
+        if region.is_empty() {
+            return;
+        }
+        if let Some(dirty) = self.dirty.iter_mut().rev().find(|x| x.touches(&region)) {
+            *dirty = dirty.union(&region);
+        } else if self.dirty.len() < MAX_DIRTY_REGIONS {
+            self.dirty.push(region);
+        } else {
+            let merged = self.dirty.iter().fold(region, |a, b| a.union(b));
+            self.dirty = vec![merged];
+        }
+    }
+
+    /// Gets a voxel for writing without marking it dirty, allocating its chunk if needed.
+    fn voxel_mut(&mut self, index: Vector4<u32>) -> &mut VoxelId {
+        self.check_bounds(index);
+        let (chunk, offset) = Self::chunk_position(index);
+        if self.chunk_index[chunk] == 0 {
+            self.voxels.extend_from_slice(&[Self::air(); CHUNK_VOLUME]);
+            self.chunk_index[chunk] = self.chunk_count() as u32;
+        }
+        let chunk = self.chunk_index[chunk] as usize - 1;
+        &mut self.voxels[chunk * CHUNK_VOLUME + offset]
+    }
+
+    /// Looks up a voxel in texture coordinates, which include the solid air border and are
+    /// reversed relative to `World` indices, matching `get_voxel` in `4d.comp`.
+    /// Returns air outside of the border.
+    pub fn get_texel(&self, location: Vector4<i32>) -> VoxelId {
+        let size = self.size as i32 + 2;
+        if location.iter().any(|&x| x < 0 || x >= size) {
+            Self::air()
+        } else if location.iter().any(|&x| x == 0 || x == size - 1) {
+            Self::solid_air()
+        } else {
+            let index = Vector4::new(location.w, location.z, location.y, location.x);
+            self[index.map(|x| x as u32 - 1)]
+        }
+    }
+
+    fn check_bounds(&self, index: Vector4<u32>) {
+        if index.iter().any(|&x| x >= self.size) {
+            panic!("Out of bounds");
+        }
+    }
+
+    /// Splits a position into the index of its chunk and the offset within the chunk.
+    fn chunk_position(index: Vector4<u32>) -> ([usize; 4], usize) {
+        let chunk = index.map(|x| (x / CHUNK_SIZE) as usize);
+        let local = index.map(|x| (x % CHUNK_SIZE) as usize);
+        let size = CHUNK_SIZE as usize;
+        let offset = ((local.x * size + local.y) * size + local.z) * size + local.w;
+        ([chunk.x, chunk.y, chunk.z, chunk.w], offset)
+    }
+
+    /// The range of chunks covering a region.
+    fn chunk_region(region: Region) -> Region {
+        Region::new(
+            region.min / CHUNK_SIZE,
+            region.max.map(|x| x.div_ceil(CHUNK_SIZE)),
+        )
+    }
+
+    /// Uploads the chunk index entries covering a region, along with their chunks if
+    /// `voxels` is set.
+    fn upload_region(&self, queue: &Queue, buffers: &WorldBuffers, region: Region, voxels: bool) {
+        let chunks = Self::chunk_region(region);
+        let chunk_index = self.chunk_index.as_slice().unwrap();
+        let n = self.chunk_index.shape()[0];
+        for x in chunks.min.x..chunks.max.x {
+            for y in chunks.min.y..chunks.max.y {
+                for z in chunks.min.z..chunks.max.z {
+                    // Chunks along `w` are contiguous in the index.
+                    let start = ((x as usize * n + y as usize) * n + z as usize) * n
+                        + chunks.min.w as usize;
+                    let end = start + (chunks.max.w - chunks.min.w) as usize;
+                    let row = &chunk_index[start..end];
+                    queue.write_buffer(
+                        &buffers.chunk_index,
+                        (start * std::mem::size_of::<u32>()) as u64,
+                        bytemuck::cast_slice(row),
+                    );
+                    if !voxels {
+                        continue;
+                    }
+                    for &chunk in row.iter().filter(|&&x| x != 0) {
+                        let start = (chunk as usize - 1) * CHUNK_VOLUME;
+                        queue.write_buffer(
+                            &buffers.voxels,
+                            start as u64,
+                            bytemuck::cast_slice(&self.voxels[start..start + CHUNK_VOLUME]),
+                        );
+                    }
+                }
+            }
         }
     }
 
     fn voxel_bytes(&self) -> &[u8] {
-        bytemuck::cast_slice(self.voxels.as_slice().unwrap())
+        bytemuck::cast_slice(&self.voxels)
     }
 
-    fn types_internal(&self) -> [VoxelTypeInternal; 256] {
+    pub fn types_internal(&self) -> [VoxelTypeInternal; 256] {
         let mut out = [Default::default(); 256];
         out[..self.types.len()].clone_from_slice(&self.types_internal);
         out
     }
 }
 
+/// Every position inside a world of the given size, with `w` varying fastest.
+pub fn positions(size: u32) -> impl Iterator<Item = Vector4<u32>> {
+    Region::cube(size).positions()
+}
+
 impl Index<Vector4<u32>> for World {
     type Output = VoxelId;
     fn index(&self, index: Vector4<u32>) -> &Self::Output {
-        let index = index.cast::<usize>() + Vector4::repeat(1);
-        let out = &self.voxels[[index.x, index.y, index.z, index.w]];
-        if *out == Self::solid_air() {
-            panic!("Out of bounds");
+        self.check_bounds(index);
+        let (chunk, offset) = Self::chunk_position(index);
+        match self.chunk_index[chunk] {
+            0 => &AIR,
+            chunk => &self.voxels[(chunk as usize - 1) * CHUNK_VOLUME + offset],
         }
-        out
-    }
-}
-impl IndexMut<Vector4<u32>> for World {
-    fn index_mut(&mut self, index: Vector4<u32>) -> &mut Self::Output {
-        let index = index.cast::<usize>() + Vector4::repeat(1);
-        let out = &mut self.voxels[[index.x, index.y, index.z, index.w]];
-        if *out == Self::solid_air() {
-            panic!("Out of bounds");
-        }
-        out
     }
 }
 
+/// GPU mirror of the world's chunk index and chunk storage, bound to `4d.comp`.
+/// `capacity` is the number of chunks the voxel buffer has room for.
 #[derive(Resource)]
-pub struct WorldTexture(pub Texture, pub Extent3d);
+pub struct WorldBuffers {
+    pub chunk_index: Buffer,
+    pub voxels: Buffer,
+    pub capacity: usize,
+}
 #[derive(Resource)]
 pub struct WorldBindGroup(pub BindGroup, pub BindGroupLayout);
 
-pub fn init_world(mut commands: Commands, size: Res<WorldSize>, device: Res<DeviceResource>) {
-    let size = size.0;
+/// The world has more chunks than fit into a voxel buffer the device can bind.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TooManyChunks {
+    pub chunks: usize,
+    pub max_chunks: usize,
+}
 
-    let world = World::new(size);
+impl fmt::Display for TooManyChunks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "The world has {} chunks, but the device can only hold {}",
+            self.chunks, self.max_chunks
+        )
+    }
+}
 
-    let size = size + 2;
+impl Error for TooManyChunks {}
 
-    let extent = Extent3d {
-        width: size,
-        height: size,
-        depth_or_array_layers: size * size,
-    };
+/// All 256 voxel type ids are taken.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TooManyTypes;
 
-    let texture = device.create_texture(&TextureDescriptor {
-        label: Some("world-texture"),
-        size: extent,
-        mip_level_count: 1,
-        sample_count: 1,
-        dimension: TextureDimension::D3,
-        format: TextureFormat::R8Uint,
-        usage: TextureUsages::COPY_DST | TextureUsages::TEXTURE_BINDING,
-    });
-    let view = texture.create_view(&TextureViewDescriptor::default());
-    let sampler = device.create_sampler(&SamplerDescriptor {
-        mag_filter: FilterMode::Nearest,
-        min_filter: FilterMode::Nearest,
-        mipmap_filter: FilterMode::Nearest,
-        ..Default::default()
-    });
+impl fmt::Display for TooManyTypes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The world can't hold more than 256 voxel types")
+    }
+}
 
-    let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-        label: Some("world-bind-group-layout"),
-        entries: &[
-            BindGroupLayoutEntry {
-                binding: 0,
-                visibility: ShaderStages::COMPUTE,
-                ty: BindingType::Texture {
-                    multisampled: false,
-                    view_dimension: TextureViewDimension::D3,
-                    sample_type: TextureSampleType::Uint,
-                },
-                count: None,
-            },
-            BindGroupLayoutEntry {
-                binding: 1,
-                visibility: ShaderStages::COMPUTE,
-                ty: BindingType::Sampler(SamplerBindingType::NonFiltering),
-                count: None,
-            },
-        ],
-    });
+impl Error for TooManyTypes {}
+
+/// How many chunks the voxel buffer should have room for to hold `chunks`, doubling so that
+/// it doesn't have to be recreated on every new chunk, but staying within the device limits.
+pub fn voxel_buffer_capacity(limits: &Limits, chunks: usize) -> Result<usize, TooManyChunks> {
+    let max_bytes = (limits.max_storage_buffer_binding_size as u64).min(limits.max_buffer_size);
+    let max_chunks = (max_bytes / CHUNK_VOLUME as u64) as usize;
+    if chunks > max_chunks {
+        return Err(TooManyChunks { chunks, max_chunks });
+    }
+    Ok(chunks.next_power_of_two().min(max_chunks))
+}
 
-    let bind_group = device.create_bind_group(&BindGroupDescriptor {
+fn create_voxel_buffer(device: &Device, capacity: usize) -> Buffer {
+    device.create_buffer(&BufferDescriptor {
+        label: Some("world-voxel-buffer"),
+        size: (capacity * CHUNK_VOLUME) as u64,
+        usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+fn create_bind_group(
+    device: &Device,
+    layout: &BindGroupLayout,
+    buffers: &WorldBuffers,
+) -> BindGroup {
+    device.create_bind_group(&BindGroupDescriptor {
         label: Some("world-bind-group"),
-        layout: &bind_group_layout,
+        layout,
         entries: &[
             BindGroupEntry {
                 binding: 0,
-                resource: BindingResource::TextureView(&view),
+                resource: buffers.chunk_index.as_entire_binding(),
             },
             BindGroupEntry {
                 binding: 1,
-                resource: BindingResource::Sampler(&sampler),
+                resource: buffers.voxels.as_entire_binding(),
             },
         ],
+    })
+}
+
+/// Creates the GPU mirror of the world, keeping a `World` that was already inserted.
+pub fn init_world(
+    mut commands: Commands,
+    size: Res<WorldSize>,
+    world: Option<Res<World>>,
+    device: Res<DeviceResource>,
+) {
+    let size = size.0;
+
+    if world.is_none() {
+        commands.insert_resource(World::new(size));
+    }
+
+    let chunks = World::chunks_per_axis(size) as u64;
+    let chunk_index = device.create_buffer(&BufferDescriptor {
+        label: Some("world-chunk-index-buffer"),
+        size: chunks.pow(4) * std::mem::size_of::<u32>() as u64,
+        usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+    let capacity = 1;
+    let buffers = WorldBuffers {
+        chunk_index,
+        voxels: create_voxel_buffer(&device, capacity),
+        capacity,
+    };
+
+    let storage_entry = |binding| BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Storage { read_only: true },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    };
+    let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: Some("world-bind-group-layout"),
+        entries: &[storage_entry(0), storage_entry(1)],
     });
 
-    commands.insert_resource(world);
-    commands.insert_resource(WorldTexture(texture, extent));
+    let bind_group = create_bind_group(&device, &bind_group_layout, &buffers);
+
+    commands.insert_resource(buffers);
     commands.insert_resource(WorldBindGroup(bind_group, bind_group_layout));
 }
 
+/// Uploads the regions of the world that were written to since the last upload.
 pub fn update_world(
-    world: Res<World>,
+    mut world: ResMut<World>,
+    device: Res<DeviceResource>,
     queue: Res<QueueResource>,
-    texture: Res<WorldTexture>,
-    mut uniforms: ResMut<uniform_3d::Uniforms>,
+    mut buffers: ResMut<WorldBuffers>,
+    mut bind_group: ResMut<WorldBindGroup>,
+    mut uniforms_3d: ResMut<uniform_3d::Uniforms>,
+    mut uniforms_4d: ResMut<uniform_4d::Uniforms>,
 ) {
     if world.is_changed() {
-        queue.write_texture(
-            ImageCopyTexture {
-                texture: &texture.0,
-                mip_level: 0,
-                origin: Origin3d::ZERO,
-                aspect: TextureAspect::All,
-            },
-            world.voxel_bytes(),
-            world.texture_layout(),
-            texture.1,
+        let mut upload_voxels = true;
+        if world.chunk_count() > buffers.capacity {
+            // Carrying on would render voxels that are no longer in the world.
+            let capacity = voxel_buffer_capacity(&device.limits(), world.chunk_count())
+                .unwrap_or_else(|err| panic!("Failed to upload the world: {}", err));
+            buffers.voxels = create_voxel_buffer(&device, capacity);
+            buffers.capacity = capacity;
+            bind_group.0 = create_bind_group(&device, &bind_group.1, &buffers);
+            queue.write_buffer(&buffers.voxels, 0, world.voxel_bytes());
+            upload_voxels = false;
+        }
+        for region in world.take_dirty() {
+            world.upload_region(&queue, &buffers, region, upload_voxels);
+        }
+        uniforms_3d.voxel_types = world.types_internal();
+        uniforms_4d.voxel_types = world.types_internal();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shapes::draw;
+
+    /// Any id but air, as the world doesn't check that its type exists.
+    const STONE: VoxelId = VoxelId(2);
+
+    fn region(min: [u32; 4], max: [u32; 4]) -> Region {
+        Region::new(Vector4::from(min), Vector4::from(max))
+    }
+
+    #[test]
+    fn overlapping_regions_merge() {
+        let mut world = World::new(16);
+        draw(&mut world, &region([0, 0, 0, 0], [4, 4, 4, 4]), VoxelId(0));
+        draw(&mut world, &region([2, 2, 2, 2], [6, 6, 6, 6]), VoxelId(0));
+        assert_eq!(world.take_dirty(), vec![region([0, 0, 0, 0], [6, 6, 6, 6])]);
+    }
+
+    #[test]
+    fn adjacent_regions_merge() {
+        let mut world = World::new(16);
+        draw(&mut world, &region([0, 0, 0, 0], [4, 4, 4, 4]), VoxelId(0));
+        draw(&mut world, &region([4, 0, 0, 0], [8, 4, 4, 4]), VoxelId(0));
+        // A run of single voxel edits becomes one region.
+        for w in 0..4 {
+            world.set(Vector4::new(8, 0, 0, w), STONE);
+        }
+        assert_eq!(world.take_dirty(), vec![region([0, 0, 0, 0], [9, 4, 4, 4])]);
+    }
+
+    #[test]
+    fn separate_regions_stay_separate() {
+        let mut world = World::new(16);
+        let a = Vector4::new(0, 0, 0, 0);
+        let b = Vector4::new(8, 8, 8, 8);
+        world.set(a, STONE);
+        world.set(b, STONE);
+        let dirty = world.take_dirty();
+        assert_eq!(dirty, vec![Region::point(a), Region::point(b)]);
+        assert!(dirty[0].contains(a) && !dirty[0].contains(b));
+    }
+
+    #[test]
+    fn regions_collapse_past_the_limit() {
+        let mut world = World::new(64);
+        let points: Vec<_> = (0..=MAX_DIRTY_REGIONS as u32)
+            .map(|i| Vector4::new(i * 2, 0, i * 3 % 7 * 2, 5))
+            .collect();
+        for &point in &points[..MAX_DIRTY_REGIONS] {
+            world.set(point, STONE);
+        }
+        assert_eq!(world.dirty.len(), MAX_DIRTY_REGIONS);
+
+        world.set(points[MAX_DIRTY_REGIONS], STONE);
+        let dirty = world.take_dirty();
+        assert_eq!(dirty.len(), 1);
+        // The merged region is the bounding box of every edit.
+        let bounding_box = points
+            .iter()
+            .fold(Region::point(points[0]), |a, &b| a.union(&Region::point(b)));
+        assert_eq!(dirty[0], bounding_box);
+        assert!(points.iter().all(|&x| dirty[0].contains(x)));
+    }
+
+    #[test]
+    fn taking_dirty_regions_clears_them() {
+        let mut world = World::new(16);
+        draw(&mut world, &region([0, 0, 0, 0], [2, 2, 2, 2]), VoxelId(0));
+        assert_eq!(world.take_dirty().len(), 1);
+        assert!(world.take_dirty().is_empty());
+        // Empty and fully clipped regions aren't recorded at all.
+        draw(&mut world, &region([3, 3, 3, 3], [3, 9, 9, 9]), VoxelId(0));
+        draw(
+            &mut world,
+            &region([20, 0, 0, 0], [30, 9, 9, 9]),
+            VoxelId(0),
+        );
+        assert!(world.take_dirty().is_empty());
+    }
+
+    #[test]
+    fn writing_air_into_an_empty_chunk_changes_nothing() {
+        let mut world = World::new(16);
+        world.set(Vector4::new(1, 2, 3, 4), World::air());
+        assert_eq!(world.chunk_count(), 0);
+        assert!(world.take_dirty().is_empty());
+
+        world.set(Vector4::new(1, 2, 3, 4), STONE);
+        world.set(Vector4::new(9, 2, 3, 5), World::air());
+        assert_eq!(world.chunk_count(), 1);
+        assert_eq!(
+            world.take_dirty(),
+            vec![Region::point(Vector4::new(1, 2, 3, 4))]
+        );
+    }
+
+    #[test]
+    fn voxel_buffer_capacity_stays_within_limits() {
+        let limits = Limits {
+            max_storage_buffer_binding_size: 100 * CHUNK_VOLUME as u32,
+            ..Default::default()
+        };
+        assert_eq!(voxel_buffer_capacity(&limits, 1), Ok(1));
+        assert_eq!(voxel_buffer_capacity(&limits, 33), Ok(64));
+        assert_eq!(voxel_buffer_capacity(&limits, 65), Ok(100));
+        assert_eq!(voxel_buffer_capacity(&limits, 100), Ok(100));
+        assert_eq!(
+            voxel_buffer_capacity(&limits, 101),
+            Err(TooManyChunks {
+                chunks: 101,
+                max_chunks: 100
+            })
         );
-        uniforms.voxel_types = world.types_internal();
     }
 }
@@ -0,0 +1,256 @@
+//! Saving and loading [`World`]s.
+//!
+//! All integers are little endian. A world file is laid out as:
+//!
+//! | Field     | Type                | Description                                         |
+//! |-----------|---------------------|-----------------------------------------------------|
+//! | magic     | `[u8; 4]`           | `R4DW`                                              |
+//! | version   | `u32`               | [`VERSION`]                                         |
+//! | size      | `u32`               | Side length of the world, excluding the border      |
+//! | types     | `u32`               | Number of voxel types, including air and solid air  |
+//! | palette   | `[[f32; 6]; types]` | Every voxel type, see below                         |
+//! | length    | `u32`               | Length of the payload in bytes                      |
+//! | payload   | `[u8; length]`      | zlib compressed voxel ids                           |
+//!
+//! The payload decompresses to `size⁴` voxel ids, ordered as [`World::positions`], so `w`
+//! varies fastest. The border is not stored. Worlds larger than [`MAX_SIZE`] are rejected.
+//!
+//! A palette entry is the sRGB color of the voxel type followed by its opacity, emissive
+//! strength and roughness.
+//!
+//! Older files are still read. Version 1 palette entries only have the color, and version 2
+//! entries end after the opacity. Missing fields make the voxel type opaque, not emissive and
+//! fully rough.
+
+use crate::voxel::{VoxelId, VoxelType};
+use crate::world::{positions, World};
+use bevy::prelude::*;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use miniz_oxide::deflate::compress_to_vec_zlib;
+use miniz_oxide::inflate::decompress_to_vec_zlib_with_limit;
+use palette::Srgb;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 4] = b"R4DW";
+pub const VERSION: u32 = 3;
+/// The largest world size that is read. Larger worlds would need more than 4 GiB of voxels.
+pub const MAX_SIZE: u32 = 256;
+/// How many bytes zlib can expand a single compressed byte to at most.
+const MAX_DEFLATE_RATIO: u64 = 1032;
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl World {
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<World> {
+        World::read_from(&mut BufReader::new(File::open(path)?))
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_u32::<LittleEndian>(VERSION)?;
+        writer.write_u32::<LittleEndian>(self.size())?;
+
+        writer.write_u32::<LittleEndian>(self.types().len() as u32)?;
+        for ty in self.types() {
+            writer.write_f32::<LittleEndian>(ty.color.red)?;
+            writer.write_f32::<LittleEndian>(ty.color.green)?;
+            writer.write_f32::<LittleEndian>(ty.color.blue)?;
+            writer.write_f32::<LittleEndian>(ty.opacity)?;
+            writer.write_f32::<LittleEndian>(ty.emissive)?;
+            writer.write_f32::<LittleEndian>(ty.roughness)?;
+        }
+
+        let voxels = self.positions().map(|x| self[x].0).collect::<Vec<_>>();
+        let payload = compress_to_vec_zlib(&voxels, 6);
+        writer.write_u32::<LittleEndian>(payload.len() as u32)?;
+        writer.write_all(&payload)
+    }
+
+    pub fn read_from(reader: &mut impl Read) -> io::Result<World> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("Not a world file"));
+        }
+        let version = reader.read_u32::<LittleEndian>()?;
+        if !(1..=VERSION).contains(&version) {
+            return Err(invalid_data("Unsupported world file version"));
+        }
+        let size = reader.read_u32::<LittleEndian>()?;
+        if !(1..=MAX_SIZE).contains(&size) {
+            return Err(invalid_data("Invalid world size"));
+        }
+
+        let type_count = reader.read_u32::<LittleEndian>()? as usize;
+        if !(2..=256).contains(&type_count) {
+            return Err(invalid_data("Invalid number of voxel types"));
+        }
+        let mut types = Vec::with_capacity(type_count);
+        for _ in 0..type_count {
+            let red = reader.read_f32::<LittleEndian>()?;
+            let green = reader.read_f32::<LittleEndian>()?;
+            let blue = reader.read_f32::<LittleEndian>()?;
+            let opacity = if version >= 2 {
+                reader.read_f32::<LittleEndian>()?
+            } else {
+                1.0
+            };
+            let (emissive, roughness) = if version >= 3 {
+                (
+                    reader.read_f32::<LittleEndian>()?,
+                    reader.read_f32::<LittleEndian>()?,
+                )
+            } else {
+                (0.0, 1.0)
+            };
+            if !(0.0..=1.0).contains(&opacity) {
+                return Err(invalid_data("Invalid voxel opacity"));
+            }
+            if !(0.0..=1.0).contains(&roughness) {
+                return Err(invalid_data("Invalid voxel roughness"));
+            }
+            let color = Srgb::new(red, green, blue);
+            types.push(VoxelType::new(color, opacity, emissive, roughness));
+        }
+
+        let length = reader.read_u32::<LittleEndian>()? as u64;
+        let voxel_count = (size as u64).pow(4);
+        if voxel_count > length * MAX_DEFLATE_RATIO {
+            return Err(invalid_data(
+                "Voxel payload is too short for the world size",
+            ));
+        }
+        // Read through `take` so that a truncated file fails before the whole length is
+        // allocated.
+        let mut payload = Vec::new();
+        reader.take(length).read_to_end(&mut payload)?;
+        if payload.len() as u64 != length {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Voxel payload is truncated",
+            ));
+        }
+        let voxels = decompress_to_vec_zlib_with_limit(&payload, voxel_count as usize)
+            .map_err(|_| invalid_data("Corrupted voxel payload"))?;
+        if voxels.len() as u64 != voxel_count {
+            return Err(invalid_data("Voxel payload does not match the world size"));
+        }
+
+        if voxels
+            .iter()
+            .any(|&id| id as usize >= type_count || VoxelId(id) == World::solid_air())
+        {
+            return Err(invalid_data("Invalid voxel id"));
+        }
+
+        let mut world = World::new(size);
+        // Air and solid air are always present.
+        for &ty in &types[2..] {
+            world.insert_type(ty);
+        }
+        // Skips air, so that only chunks with something in them are allocated.
+        for (position, id) in positions(size).zip(voxels) {
+            world.set(position, VoxelId(id));
+        }
+        Ok(world)
+    }
+}
+
+/// Where the world is saved to when pressing F5.
+#[derive(Resource, Clone, Debug)]
+pub struct WorldPath(pub PathBuf);
+
+pub fn save_world_system(key: Res<Input<KeyCode>>, world: Res<World>, path: Res<WorldPath>) {
+    if key.just_pressed(KeyCode::F5) {
+        match world.save(&path.0) {
+            Ok(()) => println!("Saved world to {}", path.0.display()),
+            Err(err) => eprintln!("Failed to save world: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxel::test_types;
+    use nalgebra::Vector4;
+    use std::io::Cursor;
+
+    fn test_world() -> World {
+        let mut world = World::new(12);
+        let stone = world.insert_type(test_types::stone());
+        let glass = world.insert_type(test_types::glass());
+        for position in world.positions().collect::<Vec<_>>() {
+            // A box from (1, 2, 3, 4) to (9, 10, 11, 12), exclusive.
+            if (0..4).all(|i| (i as u32 + 1..i as u32 + 9).contains(&position[i])) {
+                world.set(position, stone);
+            }
+        }
+        world.set(Vector4::new(0, 0, 0, 0), glass);
+        world.set(Vector4::new(11, 11, 11, 11), glass);
+        world
+    }
+
+    fn to_bytes(world: &World) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        world.write_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        let world = test_world();
+        let loaded = World::read_from(&mut Cursor::new(to_bytes(&world))).unwrap();
+        assert_eq!(loaded.size(), world.size());
+        assert_eq!(loaded.types(), world.types());
+        for position in world.positions() {
+            assert_eq!(loaded[position], world[position], "at {:?}", position);
+        }
+    }
+
+    #[test]
+    fn loading_only_allocates_chunks_with_voxels() {
+        let mut world = World::new(24);
+        let stone = world.insert_type(test_types::stone());
+        world.set(Vector4::new(20, 3, 17, 23), stone);
+        let loaded = World::read_from(&mut Cursor::new(to_bytes(&world))).unwrap();
+        assert_eq!(loaded.chunk_count(), 1);
+
+        let world = test_world();
+        let loaded = World::read_from(&mut Cursor::new(to_bytes(&world))).unwrap();
+        assert_eq!(loaded.chunk_count(), world.chunk_count());
+    }
+
+    #[test]
+    fn truncated_file_is_an_error() {
+        let bytes = to_bytes(&test_world());
+        for length in 0..bytes.len() {
+            let result = World::read_from(&mut Cursor::new(&bytes[..length]));
+            assert!(result.is_err(), "read {} of {} bytes", length, bytes.len());
+        }
+    }
+
+    #[test]
+    fn invalid_size_is_an_error() {
+        let mut bytes = to_bytes(&test_world());
+        for size in [0, MAX_SIZE + 1, u32::MAX] {
+            bytes[8..12].copy_from_slice(&size.to_le_bytes());
+            let err = World::read_from(&mut Cursor::new(&bytes)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        // Too large for a payload of this length, so it fails before allocating the world.
+        bytes[8..12].copy_from_slice(&MAX_SIZE.to_le_bytes());
+        let err = World::read_from(&mut Cursor::new(&bytes)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}